@@ -31,30 +31,20 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         ap.parse_args_or_exit();
     }
 
-    // Connecting to APNs using a client certificate
-    let new_client = || -> Result<Client, Box<dyn std::error::Error + Sync + Send>> {
-        #[cfg(feature = "openssl")]
-        {
-            // Which service to call, test or production?
-            let endpoint = if sandbox {
-                a2::Endpoint::Sandbox
-            } else {
-                a2::Endpoint::Production
-            };
+    // Which service to call, test or production?
+    let endpoint = if sandbox {
+        a2::Endpoint::Sandbox
+    } else {
+        a2::Endpoint::Production
+    };
 
-            let mut certificate = std::fs::File::open(certificate_file)?;
+    let mut certificate = std::fs::File::open(certificate_file)?;
 
-            // Create config with the given endpoint and default timeouts
-            let client_config = a2::ClientConfig::new(endpoint);
+    // Create config with the given endpoint and default timeouts
+    let client_config = a2::ClientConfig::new(endpoint);
 
-            Ok(Client::certificate(&mut certificate, &password, client_config)?)
-        }
-        #[cfg(all(not(feature = "openssl"), feature = "ring"))]
-        {
-            Err("ring does not support loading of certificates".into())
-        }
-    };
-    let client = new_client()?;
+    // Connecting to APNs using a client certificate
+    let client = Client::certificate(&mut certificate, &password, client_config)?;
 
     let options = NotificationOptions {
         apns_topic: topic.as_deref(),
@@ -0,0 +1,519 @@
+//! Building a client step by step
+
+use super::circuit_breaker::Breaker;
+use super::credentials::ReloadingCredentials;
+use super::pool::{self, Pool};
+use super::rate_limit::{DeviceLimiter, Limiter};
+use super::shutdown::Shutdown;
+use super::transport::Transport;
+use super::{
+    default_connector, https_connector, Client, ClientConfig, ConnectionOptions, Credentials, Endpoint, HyperConnector,
+    Scheme,
+};
+use crate::error::{BuilderError, Error};
+use crate::signer::Signer;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The largest HTTP/2 flow control window.
+const MAX_WINDOW_SIZE: u32 = (1 << 31) - 1;
+
+/// The smallest and the largest HTTP/2 `SETTINGS_MAX_FRAME_SIZE`.
+const MIN_FRAME_SIZE: u32 = 1 << 14;
+const MAX_FRAME_SIZE: u32 = (1 << 24) - 1;
+
+/// HTTP/2 flow control and framing settings of the connections to APNs.
+/// Unset options keep the defaults of hyper.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Http2Settings {
+    /// The initial flow control window of every stream
+    pub initial_stream_window_size: Option<u32>,
+    /// The initial flow control window of the connection
+    pub initial_connection_window_size: Option<u32>,
+    /// Size the flow control windows using the measured bandwidth-delay
+    /// product, instead of fixed initial window sizes
+    pub adaptive_window: bool,
+    /// The largest frame to receive, between 16 KiB and 16 MiB
+    pub max_frame_size: Option<u32>,
+    /// The size of the HPACK header compression table
+    pub header_table_size: Option<u32>,
+}
+
+/// Settings of the TCP connections to APNs, or to the proxy.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TcpSettings {
+    /// How long to wait for a TCP connection to be established
+    pub connect_timeout: Option<Duration>,
+    /// Disable Nagle's algorithm, sending small notifications right away
+    pub nodelay: bool,
+    /// Send TCP keepalive probes after the connection was idle this long
+    pub keepalive: Option<Duration>,
+    /// The local address to connect from
+    pub local_address: Option<IpAddr>,
+}
+
+/// Builds a [`Client`] combining a [`ClientConfig`], [`Credentials`] or a
+/// custom TLS configuration, and the tuning of the connections. Created with
+/// [`Client::builder`].
+///
+/// The combination of the options is checked by [`ClientBuilder::build`],
+/// failing with a [`BuilderError`].
+///
+/// ```no_run
+/// # use a2::client::Credentials;
+/// # use a2::{Client, Endpoint};
+/// # use std::fs::File;
+/// # use std::time::Duration;
+/// # fn build() -> Result<Client, a2::Error> {
+/// let credentials = Credentials::token(File::open("key.p8")?, "KEY_ID", "TEAM_ID")?;
+///
+/// let client = Client::builder()
+///     .endpoint(Endpoint::Sandbox)
+///     .credentials(credentials)
+///     .connect_timeout(Duration::from_secs(5))
+///     .tcp_nodelay(true)
+///     .http2_initial_stream_window_size(1 << 20)
+///     .build()?;
+/// # Ok(client)
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientBuilder {
+    config: ClientConfig,
+    signer: Option<Signer>,
+    connector: Option<HyperConnector>,
+    credentials: Option<Credentials>,
+    tls_config: Option<rustls::ClientConfig>,
+    transport: Option<Arc<dyn Transport>>,
+}
+
+impl ClientBuilder {
+    /// A builder using [`ClientConfig::default`].
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the whole configuration, including any tuning set before.
+    pub fn config(mut self, config: ClientConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The endpoint where the requests are sent to.
+    pub fn endpoint(mut self, endpoint: Endpoint) -> Self {
+        self.config.endpoint = endpoint;
+        self
+    }
+
+    /// Authenticate with a key or a certificate, which can be replaced while
+    /// the client is running.
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Connect using a TLS configuration of your own, e.g. with a client
+    /// certificate whose key is kept in a hardware module. Can't be combined
+    /// with [`ClientConfig::root_certificates`],
+    /// [`ClientConfig::pinned_certificates`] or certificate [`Credentials`],
+    /// which need a TLS configuration built by the client. ALPN is set up by
+    /// the client.
+    pub fn tls_config(mut self, tls_config: rustls::ClientConfig) -> Self {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    /// The initial HTTP/2 flow control window of every stream.
+    pub fn http2_initial_stream_window_size(mut self, size: u32) -> Self {
+        self.config.http2.initial_stream_window_size = Some(size);
+        self
+    }
+
+    /// The initial HTTP/2 flow control window of the connection.
+    pub fn http2_initial_connection_window_size(mut self, size: u32) -> Self {
+        self.config.http2.initial_connection_window_size = Some(size);
+        self
+    }
+
+    /// Size the HTTP/2 flow control windows using the measured
+    /// bandwidth-delay product. Can't be combined with fixed window sizes.
+    pub fn http2_adaptive_window(mut self, enabled: bool) -> Self {
+        self.config.http2.adaptive_window = enabled;
+        self
+    }
+
+    /// The largest HTTP/2 frame to receive, between 16 KiB and 16 MiB.
+    pub fn http2_max_frame_size(mut self, size: u32) -> Self {
+        self.config.http2.max_frame_size = Some(size);
+        self
+    }
+
+    /// Sends the requests with the transport instead of the built-in
+    /// connection pool. The connection options, i.e. TLS, proxy, HTTP/2 and
+    /// TCP settings, are then up to the transport, and so are
+    /// [`Client::connect`] and [`Client::health`].
+    pub fn transport<T: Transport>(mut self, transport: T) -> Self {
+        self.transport = Some(Arc::new(transport));
+        self
+    }
+
+    /// The size of the HPACK header compression table.
+    pub fn http2_header_table_size(mut self, size: u32) -> Self {
+        self.config.http2.header_table_size = Some(size);
+        self
+    }
+
+    /// How long to wait for a TCP connection to be established.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.tcp.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Disable Nagle's algorithm on the TCP connections.
+    pub fn tcp_nodelay(mut self, nodelay: bool) -> Self {
+        self.config.tcp.nodelay = nodelay;
+        self
+    }
+
+    /// Send TCP keepalive probes after a connection was idle this long.
+    pub fn tcp_keepalive(mut self, idle: Duration) -> Self {
+        self.config.tcp.keepalive = Some(idle);
+        self
+    }
+
+    /// The local address to connect from.
+    pub fn local_address(mut self, address: IpAddr) -> Self {
+        self.config.tcp.local_address = Some(address);
+        self
+    }
+
+    pub(crate) fn connector(mut self, connector: HyperConnector) -> Self {
+        self.connector = Some(connector);
+        self
+    }
+
+    pub(crate) fn signer(mut self, signer: Signer) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Checks the combination of the options and builds the client.
+    pub fn build(self) -> Result<Client, Error> {
+        self.validate()?;
+
+        let ClientBuilder {
+            config,
+            signer,
+            connector,
+            credentials,
+            tls_config,
+            transport,
+        } = self;
+
+        let credentials = credentials.map(|credentials| ReloadingCredentials::new(credentials, &config));
+
+        let dry_run = config.dry_run.clone();
+
+        let (pool, transport) = match (transport, &dry_run) {
+            (Some(transport), _) => (None, transport),
+            (None, Some(dry_run)) => (None, Arc::new(dry_run.clone()) as Arc<dyn Transport>),
+            (None, None) => {
+                let pool = Arc::new(Self::pool(connector, tls_config, credentials.as_ref(), &config)?);
+                (Some(pool.clone()), pool as Arc<dyn Transport>)
+            }
+        };
+
+        Ok(Client {
+            pool,
+            transport,
+            device_limiter: config
+                .device_rate_limit
+                .map(|limit| Arc::new(DeviceLimiter::new(limit))),
+            limiter: config.rate_limit.map(|limit| Arc::new(Limiter::new(limit))),
+            breaker: config.circuit_breaker.map(|breaker| Arc::new(Breaker::new(breaker))),
+            interceptors: config.interceptors.into(),
+            shutdown: Arc::new(Shutdown::default()),
+            credentials: credentials.map(Arc::new),
+            dry_run,
+            options: ConnectionOptions::new(
+                config.endpoint,
+                signer,
+                config.request_timeout_secs,
+                config.retry_policy,
+                config.token_redaction,
+            ),
+        })
+    }
+
+    fn pool(
+        connector: Option<HyperConnector>,
+        tls_config: Option<rustls::ClientConfig>,
+        credentials: Option<&ReloadingCredentials>,
+        config: &ClientConfig,
+    ) -> Result<Pool, Error> {
+        let connector = match (connector, tls_config, credentials) {
+            (Some(connector), _, _) => connector,
+            (None, Some(tls_config), _) => https_connector(tls_config, config),
+            (None, None, Some(credentials)) => credentials.connector()?,
+            (None, None, None) => default_connector(config)?,
+        };
+
+        let mut http_builder = pool::Builder::new(TokioExecutor::new());
+        http_builder
+            .keep_alive_interval(config.http2_keep_alive_interval_secs.map(Duration::from_secs))
+            .keep_alive_while_idle(config.http2_keep_alive_while_idle)
+            .initial_stream_window_size(config.http2.initial_stream_window_size)
+            .initial_connection_window_size(config.http2.initial_connection_window_size)
+            .adaptive_window(config.http2.adaptive_window)
+            .max_frame_size(config.http2.max_frame_size)
+            .header_table_size(config.http2.header_table_size)
+            .timer(TokioTimer::new());
+
+        Ok(Pool::new(http_builder, connector, config))
+    }
+
+    fn validate(&self) -> Result<(), BuilderError> {
+        let http2 = &self.config.http2;
+
+        for size in [http2.initial_stream_window_size, http2.initial_connection_window_size]
+            .into_iter()
+            .flatten()
+        {
+            if size > MAX_WINDOW_SIZE {
+                return Err(BuilderError::InvalidWindowSize(size));
+            }
+
+            if http2.adaptive_window {
+                return Err(BuilderError::AdaptiveWindowWithFixedSize);
+            }
+        }
+
+        if let Some(size) = http2.max_frame_size {
+            if !(MIN_FRAME_SIZE..=MAX_FRAME_SIZE).contains(&size) {
+                return Err(BuilderError::InvalidMaxFrameSize(size));
+            }
+        }
+
+        if self.config.tcp.connect_timeout == Some(Duration::ZERO) {
+            return Err(BuilderError::ZeroConnectTimeout);
+        }
+
+        if self.config.request_timeout_secs == Some(0) {
+            return Err(BuilderError::ZeroRequestTimeout);
+        }
+
+        if self.transport.is_some() && self.config.dry_run.is_some() {
+            return Err(BuilderError::DryRunWithTransport);
+        }
+
+        if self.transport.is_some() {
+            let connection_options = self.connector.is_some()
+                || self.tls_config.is_some()
+                || !self.config.root_certificates.is_empty()
+                || !self.config.pinned_certificates.is_empty()
+                || self.config.proxy.is_some()
+                || self.config.http2 != Http2Settings::default()
+                || self.config.tcp != TcpSettings::default();
+
+            if connection_options {
+                return Err(BuilderError::TransportWithConnectionOptions);
+            }
+
+            let client_certificate = self
+                .credentials
+                .as_ref()
+                .map_or(false, |credentials| credentials.certificate_pem().is_some());
+
+            if client_certificate {
+                return Err(BuilderError::TransportWithClientCertificate);
+            }
+        }
+
+        let custom_tls = self.tls_config.is_some()
+            || !self.config.root_certificates.is_empty()
+            || !self.config.pinned_certificates.is_empty();
+
+        if custom_tls && self.config.endpoint.scheme() == Scheme::Http {
+            return Err(BuilderError::TlsWithoutHttps);
+        }
+
+        if self.tls_config.is_some() {
+            if !self.config.root_certificates.is_empty() || !self.config.pinned_certificates.is_empty() {
+                return Err(BuilderError::TlsConfigWithCertificates);
+            }
+
+            let client_certificate = self
+                .credentials
+                .as_ref()
+                .map_or(false, |credentials| credentials.certificate_pem().is_some());
+
+            if client_certificate {
+                return Err(BuilderError::TlsConfigWithClientCertificate);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::DryRun;
+    use hyper_util::client::legacy::Client as HttpClient;
+
+    fn error(builder: ClientBuilder) -> BuilderError {
+        match builder.build() {
+            Err(Error::InvalidBuilder(e)) => e,
+            result => panic!("Expected a builder error, got {:?}", result.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_build_with_tuning() {
+        let client = ClientBuilder::new()
+            .endpoint(Endpoint::Sandbox)
+            .http2_initial_stream_window_size(1 << 20)
+            .http2_initial_connection_window_size(1 << 22)
+            .http2_max_frame_size(1 << 16)
+            .http2_header_table_size(8192)
+            .connect_timeout(Duration::from_secs(5))
+            .tcp_nodelay(true)
+            .tcp_keepalive(Duration::from_secs(60))
+            .local_address("0.0.0.0".parse().unwrap())
+            .build()
+            .unwrap();
+
+        assert_eq!(Endpoint::Sandbox, client.options.endpoint);
+    }
+
+    #[test]
+    fn test_invalid_http2_settings() {
+        assert_eq!(
+            BuilderError::InvalidWindowSize(1 << 31),
+            error(ClientBuilder::new().http2_initial_stream_window_size(1 << 31))
+        );
+        assert_eq!(
+            BuilderError::AdaptiveWindowWithFixedSize,
+            error(
+                ClientBuilder::new()
+                    .http2_adaptive_window(true)
+                    .http2_initial_connection_window_size(1 << 20)
+            )
+        );
+        assert_eq!(
+            BuilderError::InvalidMaxFrameSize(1024),
+            error(ClientBuilder::new().http2_max_frame_size(1024))
+        );
+        assert!(ClientBuilder::new().http2_adaptive_window(true).build().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_timeouts() {
+        assert_eq!(
+            BuilderError::ZeroConnectTimeout,
+            error(ClientBuilder::new().connect_timeout(Duration::ZERO))
+        );
+
+        let config = ClientConfig {
+            request_timeout_secs: Some(0),
+            ..Default::default()
+        };
+        assert_eq!(
+            BuilderError::ZeroRequestTimeout,
+            error(ClientBuilder::new().config(config))
+        );
+    }
+
+    #[test]
+    fn test_conflicting_tls_options() {
+        let root_certificates =
+            crate::client::RootCertificate::from_pem(include_bytes!("../../test_cert/ca.crt")).unwrap();
+        let tls_config = || {
+            rustls::ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_root_certificates(rustls::RootCertStore::empty())
+                .with_no_client_auth()
+        };
+
+        let config = ClientConfig {
+            root_certificates: root_certificates.clone(),
+            ..Default::default()
+        };
+        assert_eq!(
+            BuilderError::TlsConfigWithCertificates,
+            error(ClientBuilder::new().config(config).tls_config(tls_config()))
+        );
+
+        let credentials = Credentials::certificate_parts(
+            include_bytes!("../../test_cert/test.crt"),
+            include_bytes!("../../test_cert/test.key"),
+        )
+        .unwrap();
+        assert_eq!(
+            BuilderError::TlsConfigWithClientCertificate,
+            error(ClientBuilder::new().credentials(credentials).tls_config(tls_config()))
+        );
+
+        let config = ClientConfig {
+            root_certificates,
+            ..ClientConfig::new("http://127.0.0.1:8080".parse().unwrap())
+        };
+        assert_eq!(
+            BuilderError::TlsWithoutHttps,
+            error(ClientBuilder::new().config(config))
+        );
+
+        assert!(ClientBuilder::new().tls_config(tls_config()).build().is_ok());
+    }
+
+    #[test]
+    fn test_conflicting_transport_options() {
+        let transport = || {
+            HttpClient::builder(TokioExecutor::new())
+                .http2_only(true)
+                .build_http::<http_body_util::Full<hyper::body::Bytes>>()
+        };
+
+        assert_eq!(
+            BuilderError::TransportWithConnectionOptions,
+            error(ClientBuilder::new().tcp_nodelay(true).transport(transport()))
+        );
+        assert_eq!(
+            BuilderError::TransportWithConnectionOptions,
+            error(
+                ClientBuilder::new()
+                    .config(ClientConfig {
+                        proxy: Some("http://proxy.example.com:3128".parse().unwrap()),
+                        ..Default::default()
+                    })
+                    .transport(transport())
+            )
+        );
+
+        let credentials = Credentials::certificate_parts(
+            include_bytes!("../../test_cert/test.crt"),
+            include_bytes!("../../test_cert/test.key"),
+        )
+        .unwrap();
+        assert_eq!(
+            BuilderError::TransportWithClientCertificate,
+            error(ClientBuilder::new().credentials(credentials).transport(transport()))
+        );
+
+        let client = ClientBuilder::new().transport(transport()).build().unwrap();
+        assert!(client.pool.is_none());
+
+        let config = ClientConfig {
+            dry_run: Some(DryRun::new()),
+            ..Default::default()
+        };
+        assert_eq!(
+            BuilderError::DryRunWithTransport,
+            error(ClientBuilder::new().config(config).transport(transport()))
+        );
+    }
+}
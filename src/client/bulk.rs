@@ -0,0 +1,148 @@
+//! Sending one campaign to many devices
+
+use crate::error::Error;
+use crate::response::{ErrorReason, Response};
+use futures_util::stream::{BoxStream, Stream, StreamExt};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The results of a [`Client::send_all`](super::Client::send_all) call, as a
+/// stream of `(index, result)` pairs in the order the responses arrive. The
+/// index is the position of the payload in the input.
+///
+/// While the stream is consumed, the results are collected into a
+/// [`SendSummary`], available from [`SendAll::summary`].
+pub struct SendAll<'a> {
+    results: BoxStream<'a, (usize, String, Result<Response, Error>)>,
+    summary: SendSummary,
+}
+
+impl<'a> SendAll<'a> {
+    pub(crate) fn new(results: BoxStream<'a, (usize, String, Result<Response, Error>)>) -> Self {
+        Self {
+            results,
+            summary: SendSummary::default(),
+        }
+    }
+
+    /// The summary of the results received so far. Complete when the stream
+    /// has ended.
+    pub fn summary(&self) -> &SendSummary {
+        &self.summary
+    }
+
+    /// Sends the remaining notifications, discarding the individual results,
+    /// and returns the summary.
+    pub async fn finish(mut self) -> SendSummary {
+        while self.next().await.is_some() {}
+        self.summary
+    }
+}
+
+impl Stream for SendAll<'_> {
+    type Item = (usize, Result<Response, Error>);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.results.poll_next_unpin(cx) {
+            Poll::Ready(Some((index, device_token, result))) => {
+                self.summary.record(device_token, &result);
+                Poll::Ready(Some((index, result)))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl std::fmt::Debug for SendAll<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SendAll").field("summary", &self.summary).finish()
+    }
+}
+
+/// Totals over the notifications sent with
+/// [`Client::send_all`](super::Client::send_all).
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SendSummary {
+    /// Notifications accepted by APNs.
+    pub sent: usize,
+    /// Notifications rejected by APNs or failing to be sent.
+    pub failed: usize,
+    /// The number of rejections per reason given by APNs.
+    pub reasons: HashMap<ErrorReason, usize>,
+    /// Device tokens APNs reported as `Unregistered`, `BadDeviceToken` or
+    /// `DeviceTokenNotForTopic`. Stop sending to these and remove them.
+    pub invalid_tokens: Vec<String>,
+}
+
+impl SendSummary {
+    fn record(&mut self, device_token: String, result: &Result<Response, Error>) {
+        let Err(ref error) = result else {
+            self.sent += 1;
+            return;
+        };
+
+        self.failed += 1;
+
+        let Error::ResponseError(Response {
+            error: Some(ref body), ..
+        }) = error
+        else {
+            return;
+        };
+
+        *self.reasons.entry(body.reason).or_default() += 1;
+
+        if matches!(
+            body.reason,
+            ErrorReason::Unregistered | ErrorReason::BadDeviceToken | ErrorReason::DeviceTokenNotForTopic
+        ) {
+            self.invalid_tokens.push(device_token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::ErrorBody;
+    use std::time::Duration;
+
+    fn rejected(reason: ErrorReason) -> Result<Response, Error> {
+        Err(Error::ResponseError(Response {
+            error: Some(ErrorBody {
+                reason,
+                timestamp: None,
+            }),
+            apns_id: None,
+            apns_unique_id: None,
+            code: 400,
+        }))
+    }
+
+    #[test]
+    fn test_summary_counts_reasons_and_invalid_tokens() {
+        let mut summary = SendSummary::default();
+
+        let ok = Ok(Response {
+            error: None,
+            apns_id: None,
+            apns_unique_id: None,
+            code: 200,
+        });
+
+        summary.record("a".to_string(), &ok);
+        summary.record("b".to_string(), &rejected(ErrorReason::Unregistered));
+        summary.record("c".to_string(), &rejected(ErrorReason::TooManyRequests));
+        summary.record("d".to_string(), &rejected(ErrorReason::BadDeviceToken));
+        summary.record("e".to_string(), &rejected(ErrorReason::TooManyRequests));
+        summary.record("f".to_string(), &Err(Error::RequestTimeout(Duration::from_secs(20))));
+
+        assert_eq!(1, summary.sent);
+        assert_eq!(5, summary.failed);
+        assert_eq!(Some(&2), summary.reasons.get(&ErrorReason::TooManyRequests));
+        assert_eq!(Some(&1), summary.reasons.get(&ErrorReason::Unregistered));
+        assert_eq!(vec!["b".to_string(), "d".to_string()], summary.invalid_tokens);
+    }
+}
@@ -0,0 +1,418 @@
+//! Failing fast while APNs is unavailable
+
+use crate::error::Error;
+use parking_lot::{Mutex, MutexGuard};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The state of a [`CircuitBreaker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests are sent to APNs.
+    Closed,
+    /// Requests fail with [`Error::CircuitOpen`] without being sent.
+    Open,
+    /// A limited number of probe requests are sent to find out if APNs
+    /// recovered.
+    HalfOpen,
+}
+
+/// Stops sending requests to APNs for a while when too many of them fail.
+///
+/// Connection errors, timeouts and `500` or `503` responses are failures.
+/// Once at least `minimum_requests` were sent within a `window`, and the
+/// share of failures reaches `failure_rate`, the circuit opens and requests
+/// fail right away with [`Error::CircuitOpen`]. After `open_duration`, up to
+/// `probes` requests are let through. If all succeed, the circuit closes
+/// again, otherwise it opens for another `open_duration`.
+///
+/// State changes are logged as tracing events and reported to the
+/// `on_state_change` callback.
+///
+/// ```
+/// # use a2::client::{CircuitBreaker, CircuitState};
+/// # use std::time::Duration;
+/// let circuit_breaker = CircuitBreaker {
+///     failure_rate: 0.3,
+///     open_duration: Duration::from_secs(10),
+///     ..Default::default()
+/// }
+/// .on_state_change(|state| {
+///     if state == CircuitState::Open {
+///         println!("APNs is unavailable");
+///     }
+/// });
+/// ```
+#[derive(Clone)]
+pub struct CircuitBreaker {
+    /// The share of failed requests, between `0.0` and `1.0`, opening the
+    /// circuit.
+    pub failure_rate: f64,
+    /// The number of requests needed in a window before the circuit can
+    /// open.
+    pub minimum_requests: u32,
+    /// The time over which the requests are counted.
+    pub window: Duration,
+    /// How long the circuit stays open before probing.
+    pub open_duration: Duration,
+    /// The number of successful probes needed to close the circuit.
+    pub probes: u32,
+    /// Called with the new state when the state changes.
+    pub on_state_change: Option<Arc<dyn Fn(CircuitState) + Send + Sync>>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self {
+            failure_rate: 0.5,
+            minimum_requests: 20,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(30),
+            probes: 1,
+            on_state_change: None,
+        }
+    }
+}
+
+impl fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("failure_rate", &self.failure_rate)
+            .field("minimum_requests", &self.minimum_requests)
+            .field("window", &self.window)
+            .field("open_duration", &self.open_duration)
+            .field("probes", &self.probes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl CircuitBreaker {
+    /// Calls the given function with the new state when the state changes.
+    pub fn on_state_change<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(CircuitState) + Send + Sync + 'static,
+    {
+        self.on_state_change = Some(Arc::new(callback));
+        self
+    }
+
+    /// True for the errors counted as failures.
+    pub fn is_failure(error: &Error) -> bool {
+        match error {
+            Error::ConnectionError(_)
+            | Error::ClientError(_)
+            | Error::ConnectError(_)
+            | Error::Transport(_)
+            | Error::RequestTimeout(_) => true,
+            Error::ResponseError(response) => matches!(response.code, 500 | 503),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    config: CircuitBreaker,
+    state: Mutex<State>,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed {
+        started: Instant,
+        requests: u32,
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    HalfOpen {
+        in_flight: u32,
+        succeeded: u32,
+    },
+}
+
+impl State {
+    fn closed(now: Instant) -> Self {
+        State::Closed {
+            started: now,
+            requests: 0,
+            failures: 0,
+        }
+    }
+
+    fn circuit_state(&self) -> CircuitState {
+        match self {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+}
+
+/// Permission to send a request. A probe not completed with
+/// [`Permit::complete`], e.g. because the request was cancelled, frees its
+/// place for another probe.
+pub(crate) struct Permit<'a> {
+    breaker: &'a Breaker,
+    probe: bool,
+}
+
+impl Permit<'_> {
+    /// Counts the result of the request.
+    pub(crate) fn complete<T>(mut self, result: &Result<T, Error>) {
+        let failed = matches!(result, Err(ref e) if CircuitBreaker::is_failure(e));
+        let probe = std::mem::replace(&mut self.probe, false);
+
+        self.breaker.record(probe, failed, Instant::now());
+    }
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            if let State::HalfOpen { ref mut in_flight, .. } = *self.breaker.state.lock() {
+                *in_flight = in_flight.saturating_sub(1);
+            }
+        }
+    }
+}
+
+impl Breaker {
+    pub(crate) fn new(config: CircuitBreaker) -> Self {
+        Self {
+            config,
+            state: Mutex::new(State::closed(Instant::now())),
+        }
+    }
+
+    pub(crate) fn state(&self) -> CircuitState {
+        self.state.lock().circuit_state()
+    }
+
+    /// Lets a request through, or fails if the circuit is open.
+    pub(crate) fn acquire(&self) -> Result<Permit<'_>, Error> {
+        self.acquire_at(Instant::now())
+    }
+
+    fn acquire_at(&self, now: Instant) -> Result<Permit<'_>, Error> {
+        let mut state = self.state.lock();
+
+        if let State::Open { until } = *state {
+            if now < until {
+                return Err(Error::CircuitOpen);
+            }
+
+            *state = State::HalfOpen {
+                in_flight: 0,
+                succeeded: 0,
+            };
+            self.notify(state, CircuitState::HalfOpen);
+            state = self.state.lock();
+        }
+
+        match *state {
+            State::HalfOpen { ref mut in_flight, .. } if *in_flight < self.config.probes.max(1) => {
+                *in_flight += 1;
+
+                Ok(Permit {
+                    breaker: self,
+                    probe: true,
+                })
+            }
+            State::HalfOpen { .. } | State::Open { .. } => Err(Error::CircuitOpen),
+            State::Closed { .. } => Ok(Permit {
+                breaker: self,
+                probe: false,
+            }),
+        }
+    }
+
+    fn record(&self, probe: bool, failed: bool, now: Instant) {
+        let mut state = self.state.lock();
+
+        let next = match *state {
+            State::Closed {
+                ref mut started,
+                ref mut requests,
+                ref mut failures,
+            } => {
+                if now.saturating_duration_since(*started) >= self.config.window {
+                    *started = now;
+                    *requests = 0;
+                    *failures = 0;
+                }
+
+                *requests += 1;
+
+                if failed {
+                    *failures += 1;
+                }
+
+                let opens = *requests >= self.config.minimum_requests.max(1)
+                    && *failures > 0
+                    && f64::from(*failures) >= self.config.failure_rate * f64::from(*requests);
+
+                opens.then(|| State::Open {
+                    until: now + self.config.open_duration,
+                })
+            }
+            State::HalfOpen {
+                ref mut in_flight,
+                ref mut succeeded,
+            } if probe => {
+                *in_flight = in_flight.saturating_sub(1);
+
+                if failed {
+                    Some(State::Open {
+                        until: now + self.config.open_duration,
+                    })
+                } else {
+                    *succeeded += 1;
+                    (*succeeded >= self.config.probes.max(1)).then(|| State::closed(now))
+                }
+            }
+            // Results of requests sent before the circuit opened.
+            State::HalfOpen { .. } | State::Open { .. } => None,
+        };
+
+        if let Some(next) = next {
+            let circuit_state = next.circuit_state();
+            *state = next;
+            self.notify(state, circuit_state);
+        }
+    }
+
+    /// Reports a state change, after releasing the lock so the callback can
+    /// look at the client.
+    fn notify(&self, state: MutexGuard<'_, State>, circuit_state: CircuitState) {
+        drop(state);
+
+        #[cfg(feature = "tracing")]
+        {
+            tracing::warn!("Circuit breaker changed to {:?}", circuit_state);
+        }
+
+        if let Some(ref callback) = self.config.on_state_change {
+            callback(circuit_state);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::Response;
+
+    fn breaker() -> Breaker {
+        Breaker::new(CircuitBreaker {
+            failure_rate: 0.5,
+            minimum_requests: 4,
+            window: Duration::from_secs(10),
+            open_duration: Duration::from_secs(5),
+            probes: 2,
+            on_state_change: None,
+        })
+    }
+
+    fn response(code: u16) -> Error {
+        Error::ResponseError(Response {
+            error: None,
+            apns_id: None,
+            apns_unique_id: None,
+            code,
+        })
+    }
+
+    #[test]
+    fn test_failures() {
+        assert!(CircuitBreaker::is_failure(&Error::RequestTimeout(Duration::from_secs(
+            20
+        ))));
+        assert!(CircuitBreaker::is_failure(&response(500)));
+        assert!(CircuitBreaker::is_failure(&response(503)));
+        assert!(!CircuitBreaker::is_failure(&response(410)));
+        assert!(!CircuitBreaker::is_failure(&Error::QueueFull));
+    }
+
+    #[test]
+    fn test_opens_at_the_failure_rate() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        breaker.record(false, true, now);
+        breaker.record(false, true, now);
+        breaker.record(false, false, now);
+        assert_eq!(CircuitState::Closed, breaker.state());
+
+        breaker.record(false, false, now);
+        assert_eq!(CircuitState::Open, breaker.state());
+        assert!(matches!(breaker.acquire_at(now), Err(Error::CircuitOpen)));
+    }
+
+    #[test]
+    fn test_window_resets_the_counts() {
+        let breaker = breaker();
+        let now = Instant::now();
+
+        breaker.record(false, true, now);
+        breaker.record(false, true, now);
+        breaker.record(false, true, now);
+        breaker.record(false, false, now + Duration::from_secs(11));
+
+        assert_eq!(CircuitState::Closed, breaker.state());
+    }
+
+    #[test]
+    fn test_half_open_probes() {
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+
+        let breaker = Breaker::new(CircuitBreaker {
+            open_duration: Duration::from_secs(5),
+            probes: 2,
+            ..CircuitBreaker::default()
+        });
+        let breaker = Breaker {
+            config: breaker
+                .config
+                .clone()
+                .on_state_change(move |state| recorded.lock().push(state)),
+            ..breaker
+        };
+
+        let now = Instant::now();
+        *breaker.state.lock() = State::Open { until: now };
+
+        let first = breaker.acquire_at(now).unwrap();
+        let second = breaker.acquire_at(now).unwrap();
+        assert!(matches!(breaker.acquire_at(now), Err(Error::CircuitOpen)));
+
+        drop(first);
+        let first = breaker.acquire_at(now).unwrap();
+
+        first.complete::<()>(&Ok(()));
+        assert_eq!(CircuitState::HalfOpen, breaker.state());
+        second.complete::<()>(&Ok(()));
+
+        assert_eq!(CircuitState::Closed, breaker.state());
+        assert_eq!(vec![CircuitState::HalfOpen, CircuitState::Closed], *changes.lock());
+    }
+
+    #[test]
+    fn test_failed_probe_opens_again() {
+        let breaker = breaker();
+        let now = Instant::now();
+        *breaker.state.lock() = State::Open { until: now };
+
+        breaker.acquire_at(now).unwrap().complete::<()>(&Err(response(503)));
+
+        assert_eq!(CircuitState::Open, breaker.state());
+        assert!(matches!(
+            breaker.acquire_at(now + Duration::from_secs(1)),
+            Err(Error::CircuitOpen)
+        ));
+        assert!(breaker.acquire_at(now + Duration::from_secs(6)).is_ok());
+    }
+}
@@ -0,0 +1,171 @@
+//! Recording notifications instead of sending them
+
+use super::prepared::PreparedRequest;
+use super::retry;
+use super::transport::{Transport, TransportFuture};
+use crate::error::Error;
+use hyper::body::Bytes;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Keeps the requests of a client in dry-run mode, which never contacts
+/// APNs. Set as [`ClientConfig::dry_run`](super::ClientConfig::dry_run).
+///
+/// The notifications still go through the whole pipeline, i.e.
+/// serialization, validation, headers, signing, limits and interceptors, and
+/// every request is answered with a synthetic `200 OK` carrying the
+/// `apns-id` of the request, or a new one. Clones share the same buffer.
+///
+/// ```no_run
+/// # use a2::client::DryRun;
+/// # use a2::{Client, ClientConfig, DefaultNotificationBuilder, NotificationBuilder};
+/// # async fn preview() -> Result<(), a2::Error> {
+/// let dry_run = DryRun::new();
+/// let config = ClientConfig {
+///     dry_run: Some(dry_run.clone()),
+///     ..Default::default()
+/// };
+/// let client = Client::builder().config(config).build()?;
+///
+/// let payload = DefaultNotificationBuilder::new().set_body("Hi!").build("a_device_token", Default::default());
+/// client.send(payload).await?;
+///
+/// assert_eq!(1, dry_run.len());
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct DryRun {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    requests: Mutex<VecDeque<PreparedRequest>>,
+    capacity: Option<usize>,
+}
+
+impl DryRun {
+    /// A dry run keeping all requests.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A dry run keeping only the last `capacity` requests, e.g. for a
+    /// long-running staging environment.
+    pub fn with_capacity(capacity: usize) -> Self {
+        DryRun {
+            inner: Arc::new(Inner {
+                requests: Mutex::new(VecDeque::new()),
+                capacity: Some(capacity),
+            }),
+        }
+    }
+
+    /// The recorded requests, oldest first.
+    pub fn requests(&self) -> Vec<PreparedRequest> {
+        self.inner.requests.lock().iter().cloned().collect()
+    }
+
+    /// Removes and returns the recorded requests, oldest first.
+    pub fn take(&self) -> Vec<PreparedRequest> {
+        self.inner.requests.lock().drain(..).collect()
+    }
+
+    /// Removes the recorded requests.
+    pub fn clear(&self) {
+        self.inner.requests.lock().clear();
+    }
+
+    /// The number of recorded requests.
+    pub fn len(&self) -> usize {
+        self.inner.requests.lock().len()
+    }
+
+    /// True if no requests are recorded.
+    pub fn is_empty(&self) -> bool {
+        self.inner.requests.lock().is_empty()
+    }
+
+    fn record(&self, request: PreparedRequest) {
+        if self.inner.capacity == Some(0) {
+            return;
+        }
+
+        let mut requests = self.inner.requests.lock();
+
+        if Some(requests.len()) == self.inner.capacity {
+            requests.pop_front();
+        }
+
+        requests.push_back(request);
+    }
+}
+
+impl Transport for DryRun {
+    fn send(&self, request: http::Request<Bytes>) -> TransportFuture<'_> {
+        let apns_id = request
+            .headers()
+            .get("apns-id")
+            .and_then(|id| id.to_str().ok())
+            .map(String::from)
+            .unwrap_or_else(retry::new_apns_id);
+
+        self.record(request.into());
+
+        let response = http::Response::builder()
+            .header("apns-id", apns_id)
+            .body(Bytes::new())
+            .map_err(Error::BuildRequestError);
+
+        Box::pin(async move { response })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(apns_id: &str) -> http::Request<Bytes> {
+        http::Request::post("https://api.push.apple.com/3/device/a_test_id")
+            .header("apns-id", apns_id)
+            .body(Bytes::from_static(b"{}"))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_records_and_answers_with_the_apns_id() {
+        let dry_run = DryRun::new();
+
+        let response = dry_run.send(request("an-apns-id")).await.unwrap();
+
+        assert_eq!(200, response.status().as_u16());
+        assert_eq!("an-apns-id", response.headers()["apns-id"]);
+        assert_eq!(1, dry_run.clone().len());
+
+        let requests = dry_run.take();
+        assert_eq!(1, requests.len());
+        assert_eq!("an-apns-id", requests[0].headers["apns-id"]);
+        assert!(dry_run.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_keeps_the_last_requests() {
+        let dry_run = DryRun::with_capacity(2);
+
+        for apns_id in ["1", "2", "3"] {
+            dry_run.send(request(apns_id)).await.unwrap();
+        }
+
+        let apns_ids: Vec<_> = dry_run
+            .requests()
+            .iter()
+            .map(|request| request.headers["apns-id"].clone())
+            .collect();
+        assert_eq!(vec!["2", "3"], apns_ids);
+
+        dry_run.clear();
+        assert!(dry_run.is_empty());
+    }
+}
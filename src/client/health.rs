@@ -0,0 +1,106 @@
+//! The state of the connections to APNs
+
+use std::time::{Duration, Instant};
+
+/// The state of one connection of the [`Client`](super::Client).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// The connection was not opened yet.
+    NotConnected,
+    /// The connection was opened, and the last request over it, if any, got
+    /// a response.
+    Connected,
+    /// Opening the connection or the last request over it failed with a
+    /// transport error. The connection is opened again for the next request.
+    Failed,
+}
+
+/// The health of one connection of the [`Client`](super::Client).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionHealth {
+    /// The state after the last request.
+    pub state: ConnectionState,
+    /// The round-trip time of the last HTTP/2 `PING`, or of the `SETTINGS`
+    /// exchange when opening the connection.
+    pub last_rtt: Option<Duration>,
+    /// When the last response was received.
+    pub last_response: Option<Instant>,
+    /// The number of requests sent or waiting to be sent.
+    pub in_flight: usize,
+}
+
+/// The health of all connections of the [`Client`](super::Client), as
+/// returned by [`Client::health`](super::Client::health).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Health {
+    /// One entry per connection.
+    pub connections: Vec<ConnectionHealth>,
+}
+
+impl Health {
+    /// True if at least one connection is connected.
+    pub fn is_connected(&self) -> bool {
+        self.connections
+            .iter()
+            .any(|connection| connection.state == ConnectionState::Connected)
+    }
+
+    /// The shortest round-trip time over all connections.
+    pub fn min_rtt(&self) -> Option<Duration> {
+        self.connections
+            .iter()
+            .filter_map(|connection| connection.last_rtt)
+            .min()
+    }
+}
+
+/// The health of a connection, updated by the pool.
+#[derive(Debug)]
+pub(crate) struct Status {
+    pub(crate) state: ConnectionState,
+    pub(crate) last_response: Option<Instant>,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::NotConnected,
+            last_response: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn connection(state: ConnectionState, last_rtt: Option<u64>) -> ConnectionHealth {
+        ConnectionHealth {
+            state,
+            last_rtt: last_rtt.map(Duration::from_millis),
+            last_response: None,
+            in_flight: 0,
+        }
+    }
+
+    #[test]
+    fn test_health() {
+        let health = Health {
+            connections: vec![
+                connection(ConnectionState::Failed, Some(20)),
+                connection(ConnectionState::Connected, Some(30)),
+                connection(ConnectionState::NotConnected, None),
+            ],
+        };
+
+        assert!(health.is_connected());
+        assert_eq!(Some(Duration::from_millis(20)), health.min_rtt());
+
+        let health = Health {
+            connections: vec![connection(ConnectionState::Failed, None)],
+        };
+
+        assert!(!health.is_connected());
+        assert_eq!(None, health.min_rtt());
+    }
+}
@@ -0,0 +1,67 @@
+//! Hooks around every request sent to APNs
+
+use crate::error::Error;
+use crate::response::Response;
+use hyper::body::Bytes;
+use std::fmt;
+use std::ops::ControlFlow;
+use std::time::Duration;
+
+/// Hooks called by the [`Client`](super::Client) around every request to
+/// APNs, e.g. for adding headers, stamping correlation ids or writing audit
+/// logs. Registered with
+/// [`ClientConfig::interceptors`](super::ClientConfig::interceptors) and
+/// called in that order.
+///
+/// With a [`RetryPolicy`](super::RetryPolicy), the hooks are called for
+/// every attempt.
+///
+/// ```
+/// # use a2::client::Interceptor;
+/// # use a2::{Error, Response};
+/// # use hyper::body::Bytes;
+/// # use std::ops::ControlFlow;
+/// # use std::time::Duration;
+/// #[derive(Debug)]
+/// struct CorrelationId;
+///
+/// impl Interceptor for CorrelationId {
+///     fn before_send(&self, request: &mut http::Request<Bytes>) -> ControlFlow<Result<Response, Error>> {
+///         request.headers_mut().insert("x-correlation-id", http::HeaderValue::from_static("1234"));
+///         ControlFlow::Continue(())
+///     }
+///
+///     fn after_response(
+///         &self,
+///         request: &http::Request<()>,
+///         result: &mut Result<Response, Error>,
+///         elapsed: Duration,
+///     ) -> ControlFlow<()> {
+///         println!("{} took {:?}: {:?}", request.uri(), elapsed, result);
+///         ControlFlow::Continue(())
+///     }
+/// }
+/// ```
+pub trait Interceptor: fmt::Debug + Send + Sync {
+    /// Called with the request about to be sent, which can be modified.
+    /// Returning `Break` with a result skips the following interceptors and
+    /// returns the result without sending the request.
+    fn before_send(&self, request: &mut http::Request<Bytes>) -> ControlFlow<Result<Response, Error>> {
+        let _ = request;
+        ControlFlow::Continue(())
+    }
+
+    /// Called with the method, URI and headers of the request, the result and
+    /// the time it took, also for requests short-circuited by `before_send`.
+    /// The result can be replaced. Returning `Break` skips the following
+    /// interceptors.
+    fn after_response(
+        &self,
+        request: &http::Request<()>,
+        result: &mut Result<Response, Error>,
+        elapsed: Duration,
+    ) -> ControlFlow<()> {
+        let _ = (request, result, elapsed);
+        ControlFlow::Continue(())
+    }
+}
@@ -0,0 +1,178 @@
+//! Recording metrics of the requests sent to APNs
+//!
+//! Metrics are recorded using the [`metrics`] facade, and exported by the
+//! recorder the application installs:
+//!
+//! - `a2_requests_total`: requests answered by APNs, by `status`
+//! - `a2_failures_total`: failed requests, by `reason`, which is either the
+//!   [`ErrorReason`] given by APNs or the kind of the error
+//! - `a2_timeouts_total`: requests timing out
+//! - `a2_request_duration_seconds`: a histogram of the request latency
+//! - `a2_requests_in_flight`: a gauge of the requests waiting for a response
+//! - `a2_token_renewals_total`: renewals of the JWT authentication token
+//!
+//! All but the last one are labeled with the `endpoint` and the `topic`.
+
+use crate::error::Error;
+use crate::response::Response;
+use hyper::body::Bytes;
+use metrics::{counter, gauge, histogram, Gauge};
+use std::time::Instant;
+
+use super::Endpoint;
+
+/// Records the metrics of one request, counting it in flight until dropped.
+pub(crate) struct RequestMetrics {
+    labels: [(&'static str, String); 2],
+    in_flight: Gauge,
+    started: Instant,
+}
+
+impl RequestMetrics {
+    pub(crate) fn start(endpoint: &Endpoint, request: &http::Request<Bytes>) -> Self {
+        let topic = request
+            .headers()
+            .get("apns-topic")
+            .and_then(|topic| topic.to_str().ok())
+            .unwrap_or_default();
+
+        let labels = [("endpoint", endpoint.to_string()), ("topic", topic.to_string())];
+
+        let in_flight = gauge!("a2_requests_in_flight", &labels);
+        in_flight.increment(1);
+
+        Self {
+            labels,
+            in_flight,
+            started: Instant::now(),
+        }
+    }
+
+    pub(crate) fn finish(self, result: &Result<Response, Error>) {
+        histogram!("a2_request_duration_seconds", &self.labels).record(self.started.elapsed());
+
+        let status = match result {
+            Ok(response) | Err(Error::ResponseError(response)) => Some(response.code),
+            Err(_) => None,
+        };
+
+        if let Some(status) = status {
+            let mut labels = self.labels.to_vec();
+            labels.push(("status", status.to_string()));
+            counter!("a2_requests_total", &labels).increment(1);
+        }
+
+        if let Err(ref error) = result {
+            if let Error::RequestTimeout(_) = error {
+                counter!("a2_timeouts_total", &self.labels).increment(1);
+            }
+
+            let mut labels = self.labels.to_vec();
+            labels.push(("reason", failure_reason(error)));
+            counter!("a2_failures_total", &labels).increment(1);
+        }
+    }
+}
+
+impl Drop for RequestMetrics {
+    fn drop(&mut self) {
+        self.in_flight.decrement(1);
+    }
+}
+
+fn failure_reason(error: &Error) -> String {
+    match error {
+        Error::ResponseError(Response {
+            error: Some(ref body), ..
+        }) => format!("{:?}", body.reason),
+        Error::ResponseError(_) => "Unknown".to_string(),
+        Error::ConnectionError(_) => "ConnectionError".to_string(),
+        Error::ClientError(_) => "ClientError".to_string(),
+        Error::ConnectError(_) => "ConnectError".to_string(),
+        Error::Transport(_) => "Transport".to_string(),
+        Error::RequestTimeout(_) => "RequestTimeout".to_string(),
+        Error::QueueFull => "QueueFull".to_string(),
+        Error::DeviceRateLimited => "DeviceRateLimited".to_string(),
+        Error::CircuitOpen => "CircuitOpen".to_string(),
+        Error::Shutdown => "Shutdown".to_string(),
+        Error::UnknownTenant(_) => "UnknownTenant".to_string(),
+        _ => "Other".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{ErrorBody, ErrorReason};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use std::time::Duration;
+
+    #[test]
+    fn test_request_metrics() {
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        metrics::with_local_recorder(&recorder, || {
+            let request = http::Request::builder()
+                .uri("https://api.push.apple.com/3/device/a_test_id")
+                .header("apns-topic", "a_topic")
+                .body(Bytes::new())
+                .unwrap();
+
+            RequestMetrics::start(&Endpoint::Production, &request).finish(&Err(Error::ResponseError(Response {
+                error: Some(ErrorBody {
+                    reason: ErrorReason::Unregistered,
+                    timestamp: None,
+                }),
+                apns_id: None,
+                apns_unique_id: None,
+                code: 410,
+            })));
+
+            RequestMetrics::start(&Endpoint::Production, &request)
+                .finish(&Err(Error::RequestTimeout(Duration::from_secs(20))));
+        });
+
+        let metrics: Vec<_> = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key();
+                let labels: Vec<String> = key.labels().map(|l| format!("{}={}", l.key(), l.value())).collect();
+                (key.name().to_string(), labels.join(","), value)
+            })
+            .collect();
+
+        let find = |name: &str, labels: &str| {
+            metrics
+                .iter()
+                .find(|(n, l, _)| n == name && l == labels)
+                .map(|(_, _, value)| value)
+        };
+
+        let base = "endpoint=https://api.push.apple.com,topic=a_topic";
+
+        assert_eq!(
+            Some(&DebugValue::Counter(1)),
+            find("a2_requests_total", &format!("{},status=410", base))
+        );
+        assert_eq!(
+            Some(&DebugValue::Counter(1)),
+            find("a2_failures_total", &format!("{},reason=Unregistered", base))
+        );
+        assert_eq!(
+            Some(&DebugValue::Counter(1)),
+            find("a2_failures_total", &format!("{},reason=RequestTimeout", base))
+        );
+        assert_eq!(Some(&DebugValue::Counter(1)), find("a2_timeouts_total", base));
+        assert_eq!(
+            Some(&DebugValue::Gauge(0.0.into())),
+            find("a2_requests_in_flight", base)
+        );
+        assert!(matches!(
+            find("a2_request_duration_seconds", base),
+            Some(DebugValue::Histogram(values)) if values.len() == 2
+        ));
+    }
+}
@@ -0,0 +1,614 @@
+//! Spreading requests over several HTTP/2 connections
+
+use http::Uri;
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper_util::rt::TokioExecutor;
+use parking_lot::{Mutex, RwLock};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+use tower_service::Service;
+
+use super::health::{ConnectionHealth, ConnectionState, Health, Status};
+use super::streams::{RoundTrips, Stream, StreamLimit, StreamPermit};
+use super::{ClientConfig, HyperConnector};
+use crate::error::Error;
+
+type Body = BoxBody<Bytes, Infallible>;
+
+pub(crate) type Builder = http2::Builder<TokioExecutor>;
+
+/// How the [`Client`](super::Client) picks a connection for a request, when
+/// [`ClientConfig::connections`](super::ClientConfig::connections) is more than
+/// one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LoadBalancing {
+    /// Use every connection in turn.
+    #[default]
+    RoundRobin,
+    /// Use the connection with the fewest requests waiting for a response.
+    LeastInFlight,
+}
+
+/// A fixed number of HTTP/2 connections, opened on the first request or
+/// when warming up. A connection failing with a transport error, closed by
+/// the server or idle for longer than the idle timeout is replaced with a
+/// fresh one, while requests already sent over the old connection are
+/// allowed to finish.
+///
+/// Each connection allows `initial_concurrent_streams` requests at once until
+/// the server announces its `SETTINGS_MAX_CONCURRENT_STREAMS`, then the
+/// announced value, capped to `max_concurrent_streams`. A request picked for
+/// a busy connection goes to the next one with a free stream. Requests over
+/// the limit of every connection wait in a queue of bounded capacity.
+#[derive(Debug)]
+pub(crate) struct Pool {
+    slots: Vec<Slot>,
+    load_balancing: LoadBalancing,
+    next: AtomicUsize,
+    builder: Builder,
+    connector: RwLock<HyperConnector>,
+    idle_timeout: Option<Duration>,
+    initial_streams: usize,
+    max_streams: usize,
+    queue_capacity: usize,
+    queued: AtomicUsize,
+    released: Notify,
+}
+
+#[derive(Debug)]
+struct Slot {
+    connection: RwLock<Arc<Connection>>,
+    in_flight: AtomicUsize,
+    status: Mutex<Status>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    state: tokio::sync::Mutex<State>,
+    streams: Arc<StreamLimit>,
+    round_trips: Arc<RoundTrips>,
+    last_used: Mutex<Instant>,
+}
+
+/// A connection is opened at most once, a closed one is replaced.
+#[derive(Debug)]
+enum State {
+    New,
+    Open(SendRequest<Body>),
+    Closed,
+}
+
+/// Wakes up the tasks waiting for capacity when dropped, after the stream
+/// permit of a request is released.
+struct Release<'a>(&'a Notify);
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        self.0.notify_waiters();
+    }
+}
+
+/// A stream taken on a connection ahead of the request, e.g. by the `tower`
+/// service when becoming ready. Sending a request with the reservation in
+/// its extensions uses the stream, instead of picking a connection.
+#[derive(Debug)]
+pub(crate) struct Reservation {
+    pool: Arc<Pool>,
+    index: usize,
+    connection: Arc<Connection>,
+    stream: Option<StreamPermit>,
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        self.stream.take();
+        self.pool.released.notify_waiters();
+    }
+}
+
+/// The request extension holding a [`Reservation`], taken by the first
+/// attempt sending the request.
+#[derive(Debug, Clone)]
+pub(crate) struct Reserved(Arc<Mutex<Option<Reservation>>>);
+
+impl Reserved {
+    pub(crate) fn new(reservation: Reservation) -> Self {
+        Self(Arc::new(Mutex::new(Some(reservation))))
+    }
+}
+
+/// Keeps a request counted until dropped, also when the request is
+/// cancelled.
+struct Counted<'a>(&'a AtomicUsize);
+
+impl<'a> Counted<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+
+    /// Counts the request only if the counter stays within `capacity`.
+    fn within(counter: &'a AtomicUsize, capacity: usize) -> Option<Self> {
+        counter
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < capacity).then(|| count + 1)
+            })
+            .ok()
+            .map(|_| Self(counter))
+    }
+}
+
+impl Drop for Counted<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Pool {
+    pub(crate) fn new(builder: Builder, connector: HyperConnector, config: &ClientConfig) -> Self {
+        let max_streams = (config.max_concurrent_streams as usize).max(1);
+        let initial_streams = (config.initial_concurrent_streams as usize).clamp(1, max_streams);
+
+        let mut pool = Self {
+            slots: Vec::new(),
+            load_balancing: config.load_balancing,
+            next: AtomicUsize::new(0),
+            builder,
+            connector: RwLock::new(connector),
+            idle_timeout: config.pool_idle_timeout_secs.map(Duration::from_secs),
+            initial_streams,
+            max_streams,
+            queue_capacity: config.send_queue_capacity,
+            queued: AtomicUsize::new(0),
+            released: Notify::new(),
+        };
+
+        pool.slots = (0..config.connections.max(1))
+            .map(|_| Slot {
+                connection: RwLock::new(pool.connection()),
+                in_flight: AtomicUsize::new(0),
+                status: Mutex::new(Status::default()),
+            })
+            .collect();
+
+        pool
+    }
+
+    fn connection(&self) -> Arc<Connection> {
+        Arc::new(Connection {
+            state: tokio::sync::Mutex::new(State::New),
+            streams: Arc::new(StreamLimit::new(self.initial_streams, self.max_streams)),
+            round_trips: Arc::new(RoundTrips::default()),
+            last_used: Mutex::new(Instant::now()),
+        })
+    }
+
+    /// Opens the connection to `dst` if it's new, waiting for another task
+    /// opening it. `None` if the connection is closed.
+    async fn open(&self, connection: &Arc<Connection>, dst: &Uri) -> Result<Option<SendRequest<Body>>, Error> {
+        let mut state = connection.state.lock().await;
+
+        match *state {
+            State::Open(ref sender) if !sender.is_closed() => return Ok(Some(sender.clone())),
+            State::New => (),
+            _ => {
+                *state = State::Closed;
+                return Ok(None);
+            }
+        }
+
+        *state = State::Closed;
+
+        let mut connector = self.connector.read().clone();
+        futures_util::future::poll_fn(|cx| connector.poll_ready(cx))
+            .await
+            .map_err(Error::ConnectError)?;
+        let io = connector.call(dst.clone()).await.map_err(Error::ConnectError)?;
+
+        let io = Stream::new(io, connection.streams.clone(), connection.round_trips.clone());
+        let (sender, conn) = self.builder.handshake(io).await?;
+        let round_trips = connection.round_trips.clone();
+
+        tokio::spawn(async move {
+            if let Err(_e) = conn.await {
+                #[cfg(feature = "tracing")]
+                {
+                    tracing::debug!("Pool connection closed with an error: {}", _e);
+                }
+            }
+
+            round_trips.close();
+        });
+
+        *connection.last_used.lock() = Instant::now();
+        self.close_when_idle(connection);
+        *state = State::Open(sender.clone());
+
+        Ok(Some(sender))
+    }
+
+    /// Closes the connection once it's been idle for the idle timeout.
+    fn close_when_idle(&self, connection: &Arc<Connection>) {
+        let Some(timeout) = self.idle_timeout else {
+            return;
+        };
+
+        let connection = Arc::downgrade(connection);
+
+        tokio::spawn(async move {
+            let mut wait = timeout;
+
+            loop {
+                tokio::time::sleep(wait).await;
+
+                let Some(connection) = Weak::upgrade(&connection) else {
+                    return;
+                };
+
+                let idle = connection.last_used.lock().elapsed();
+
+                if idle < timeout {
+                    wait = timeout - idle;
+                } else if connection.streams.in_use() > 0 {
+                    wait = timeout;
+                } else {
+                    #[cfg(feature = "tracing")]
+                    {
+                        tracing::debug!("Pool closing a connection idle for {:?}", idle);
+                    }
+
+                    *connection.state.lock().await = State::Closed;
+                    return;
+                }
+            }
+        });
+    }
+
+    pub(crate) async fn request(&self, mut request: hyper::Request<Body>) -> Result<hyper::Response<Incoming>, Error> {
+        let reservation = request
+            .extensions_mut()
+            .remove::<Reserved>()
+            .and_then(|reserved| reserved.0.lock().take());
+
+        match reservation {
+            Some(reservation) => self.request_on(reservation.index, request, Some(reservation)).await,
+            None => self.request_on(self.pick(), request, None).await,
+        }
+    }
+
+    /// Opens every connection not open yet to `dst`, without sending a
+    /// request, and waits for the server to acknowledge the settings of the
+    /// client, which measures the first round-trip time.
+    pub(crate) async fn warm_up(&self, dst: &Uri, timeout: Duration) -> Result<(), Error> {
+        let warm_ups = (0..self.slots.len()).map(|index| async move {
+            let started = Instant::now();
+
+            match tokio::time::timeout(timeout, self.warm_up_on(index, dst)).await {
+                Ok(result) => result,
+                Err(_) => Err(Error::RequestTimeout(started.elapsed())),
+            }
+        });
+
+        futures_util::future::join_all(warm_ups).await.into_iter().collect()
+    }
+
+    async fn warm_up_on(&self, index: usize, dst: &Uri) -> Result<(), Error> {
+        let slot = &self.slots[index];
+
+        let connection = loop {
+            let connection = slot.connection.read().clone();
+
+            match self.open(&connection, dst).await {
+                Ok(Some(_)) => break connection,
+                Ok(None) => self.replace(index, &connection),
+                Err(e) => {
+                    self.fail(index, &connection);
+                    return Err(e);
+                }
+            }
+        };
+
+        if connection.round_trips.measured().await.is_none() {
+            self.fail(index, &connection);
+            return Err(Error::ConnectError(
+                "the connection closed during the HTTP/2 handshake".into(),
+            ));
+        }
+
+        slot.status.lock().state = ConnectionState::Connected;
+
+        Ok(())
+    }
+
+    pub(crate) fn health(&self) -> Health {
+        let connections = self
+            .slots
+            .iter()
+            .map(|slot| {
+                let status = slot.status.lock();
+
+                ConnectionHealth {
+                    state: status.state,
+                    last_rtt: slot.connection.read().round_trips.last(),
+                    last_response: status.last_response,
+                    in_flight: slot.in_flight.load(Ordering::Relaxed),
+                }
+            })
+            .collect();
+
+        Health { connections }
+    }
+
+    async fn request_on(
+        &self,
+        index: usize,
+        request: hyper::Request<Body>,
+        mut reservation: Option<Reservation>,
+    ) -> Result<hyper::Response<Incoming>, Error> {
+        let _release = Release(&self.released);
+
+        let (index, connection, _in_flight, _stream, mut sender) = loop {
+            let current = self.slots[index].connection.read().clone();
+
+            // The connection might have been replaced since the reservation.
+            let reserved = reservation
+                .take()
+                .filter(|reservation| Arc::ptr_eq(&reservation.connection, &current))
+                .and_then(|mut reservation| reservation.stream.take())
+                .map(|stream| (index, current.clone(), stream));
+
+            // A busy connection sends the request over another one with a
+            // free stream, before waiting in the queue.
+            let (index, connection, in_flight, stream) = match reserved.or_else(|| self.try_acquire_from(index)) {
+                Some((index, connection, stream)) => {
+                    (index, connection, Counted::new(&self.slots[index].in_flight), stream)
+                }
+                None => {
+                    let in_flight = Counted::new(&self.slots[index].in_flight);
+                    let _queued = Counted::within(&self.queued, self.queue_capacity).ok_or(Error::QueueFull)?;
+                    let stream = current.streams.acquire().await;
+
+                    (index, current, in_flight, stream)
+                }
+            };
+
+            match self.open(&connection, request.uri()).await {
+                Ok(Some(sender)) => break (index, connection, in_flight, stream, sender),
+                Ok(None) => self.replace(index, &connection),
+                Err(e) => {
+                    self.fail(index, &connection);
+                    return Err(e);
+                }
+            }
+        };
+
+        let result = sender.send_request(request).await;
+        *connection.last_used.lock() = Instant::now();
+
+        match result {
+            Ok(_) => {
+                let mut status = self.slots[index].status.lock();
+                status.state = ConnectionState::Connected;
+                status.last_response = Some(Instant::now());
+            }
+            Err(_) => self.fail(index, &connection),
+        }
+
+        Ok(result?)
+    }
+
+    /// Records a transport error on the connection and replaces it.
+    fn fail(&self, index: usize, connection: &Arc<Connection>) {
+        self.slots[index].status.lock().state = ConnectionState::Failed;
+
+        #[cfg(feature = "tracing")]
+        {
+            tracing::debug!("Pool replacing connection {} after a transport error", index);
+        }
+
+        self.replace(index, connection);
+    }
+
+    /// Replaces the connection with a fresh one, unless another request
+    /// replaced it already.
+    fn replace(&self, index: usize, connection: &Arc<Connection>) {
+        let mut current = self.slots[index].connection.write();
+
+        if Arc::ptr_eq(&current, connection) {
+            *current = self.connection();
+        }
+    }
+
+    /// Uses the connector for new connections, replacing the current ones,
+    /// which close once the requests still using them are done.
+    pub(crate) fn set_connector(&self, connector: HyperConnector) {
+        *self.connector.write() = connector;
+
+        for slot in &self.slots {
+            *slot.connection.write() = self.connection();
+        }
+    }
+
+    /// Drops the connections, closing them once the requests still using
+    /// them are done.
+    pub(crate) fn close(&self) {
+        for slot in &self.slots {
+            *slot.connection.write() = self.connection();
+            *slot.status.lock() = Status::default();
+        }
+    }
+
+    /// Takes a stream on a connection with room for another request,
+    /// starting with the one the load balancing picks.
+    pub(crate) fn try_reserve(self: &Arc<Self>) -> Option<Reservation> {
+        let (index, connection, stream) = self.try_acquire_from(self.pick())?;
+
+        Some(Reservation {
+            pool: self.clone(),
+            index,
+            connection,
+            stream: Some(stream),
+        })
+    }
+
+    /// Takes a stream on the first connection with room for another
+    /// request, starting with the connection at `first`.
+    fn try_acquire_from(&self, first: usize) -> Option<(usize, Arc<Connection>, StreamPermit)> {
+        (0..self.slots.len()).find_map(|i| {
+            let index = (first + i) % self.slots.len();
+            let connection = self.slots[index].connection.read().clone();
+            let stream = connection.streams.try_acquire()?;
+
+            Some((index, connection, stream))
+        })
+    }
+
+    /// True if a request could be sent right away, without waiting in the
+    /// queue.
+    fn has_capacity(&self) -> bool {
+        self.slots
+            .iter()
+            .any(|slot| slot.connection.read().streams.available() > 0)
+    }
+
+    /// Waits until a request could be sent right away.
+    pub(crate) async fn wait_for_capacity(&self) {
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+
+            if self.has_capacity() {
+                return;
+            }
+
+            released.await;
+        }
+    }
+
+    fn pick(&self) -> usize {
+        match self.load_balancing {
+            LoadBalancing::RoundRobin => self.next.fetch_add(1, Ordering::Relaxed) % self.slots.len(),
+            LoadBalancing::LeastInFlight => {
+                // Start from a rotating offset, so ties don't always go to the
+                // first connection.
+                let offset = self.next.fetch_add(1, Ordering::Relaxed);
+
+                (0..self.slots.len())
+                    .map(|i| (i + offset) % self.slots.len())
+                    .min_by_key(|i| self.slots[*i].in_flight.load(Ordering::Relaxed))
+                    .unwrap_or(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::default_connector;
+
+    fn pool(connections: usize, load_balancing: LoadBalancing) -> Pool {
+        let config = ClientConfig {
+            connections,
+            load_balancing,
+            ..Default::default()
+        };
+        let connector = default_connector(&config).unwrap();
+
+        Pool::new(Builder::new(TokioExecutor::new()), connector, &config)
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let pool = pool(3, LoadBalancing::RoundRobin);
+        let picked: Vec<usize> = (0..6).map(|_| pool.pick()).collect();
+
+        assert_eq!(vec![0, 1, 2, 0, 1, 2], picked);
+    }
+
+    #[test]
+    fn test_least_in_flight() {
+        let pool = pool(3, LoadBalancing::LeastInFlight);
+
+        let _first = Counted::new(&pool.slots[0].in_flight);
+        let _second = Counted::new(&pool.slots[1].in_flight);
+        assert_eq!(2, pool.pick());
+
+        let _third = Counted::new(&pool.slots[2].in_flight);
+        let _fourth = Counted::new(&pool.slots[2].in_flight);
+        drop(_first);
+        assert_eq!(0, pool.pick());
+    }
+
+    #[test]
+    fn test_at_least_one_connection() {
+        let pool = pool(0, LoadBalancing::RoundRobin);
+
+        assert_eq!(1, pool.slots.len());
+        assert_eq!(0, pool.pick());
+    }
+
+    #[test]
+    fn test_stream_limits() {
+        let config = ClientConfig {
+            initial_concurrent_streams: 5,
+            max_concurrent_streams: 2,
+            ..Default::default()
+        };
+        let connector = default_connector(&config).unwrap();
+        let pool = Pool::new(Builder::new(TokioExecutor::new()), connector, &config);
+
+        assert_eq!(2, pool.initial_streams);
+        assert_eq!(2, pool.max_streams);
+        assert_eq!(2, pool.slots[0].connection.read().streams.available());
+    }
+
+    #[test]
+    fn test_busy_connections_are_skipped() {
+        let config = ClientConfig {
+            connections: 3,
+            initial_concurrent_streams: 1,
+            max_concurrent_streams: 1,
+            ..Default::default()
+        };
+        let connector = default_connector(&config).unwrap();
+        let pool = Pool::new(Builder::new(TokioExecutor::new()), connector, &config);
+
+        let (first, _, _first) = pool.try_acquire_from(1).unwrap();
+        let (second, _, _second) = pool.try_acquire_from(1).unwrap();
+        let (third, _, _third) = pool.try_acquire_from(1).unwrap();
+
+        assert_eq!(vec![1, 2, 0], vec![first, second, third]);
+        assert!(pool.try_acquire_from(1).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_capacity() {
+        let pool = Arc::new(pool(2, LoadBalancing::RoundRobin));
+        assert!(pool.has_capacity());
+
+        let first = pool.try_reserve().unwrap();
+        let second = pool.try_reserve().unwrap();
+        assert_ne!(first.index, second.index);
+        assert!(!pool.has_capacity());
+        assert!(pool.try_reserve().is_none());
+
+        let waiting = tokio::spawn({
+            let pool = pool.clone();
+            async move { pool.wait_for_capacity().await }
+        });
+
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        drop(first);
+        waiting.await.unwrap();
+        drop(second);
+    }
+}
@@ -0,0 +1,204 @@
+//! Signed requests and their responses, for sending with other HTTP clients
+
+use crate::error::Error;
+use crate::response::Response;
+use http::header::AUTHORIZATION;
+use http::{HeaderMap, Method, StatusCode, Uri};
+use hyper::body::Bytes;
+use std::fmt;
+
+/// A notification request as sent by the [`Client`](super::Client), signed
+/// and ready to go over the wire. Created with
+/// [`Client::prepare`](super::Client::prepare).
+///
+/// A token-based `authorization` header is valid for up to an hour, so a
+/// request kept for replaying later might need to be prepared again.
+///
+/// The `Debug` output hides the `authorization` header, the device token in
+/// the URI and the payload, showing only its size.
+#[derive(Clone, PartialEq)]
+pub struct PreparedRequest {
+    /// Always `POST`
+    pub method: Method,
+    /// The absolute URI of the device token at the endpoint
+    pub uri: Uri,
+    /// The APNs headers, including the `authorization` header with a JWT
+    /// when using token-based authentication
+    pub headers: HeaderMap,
+    /// The JSON payload
+    pub body: Bytes,
+}
+
+impl PreparedRequest {
+    /// The request for sending it with any HTTP/2 client.
+    pub fn into_request(self) -> http::Request<Bytes> {
+        let mut request = http::Request::new(self.body);
+        *request.method_mut() = self.method;
+        *request.uri_mut() = self.uri;
+        *request.headers_mut() = self.headers;
+        request
+    }
+}
+
+impl From<http::Request<Bytes>> for PreparedRequest {
+    fn from(request: http::Request<Bytes>) -> Self {
+        let (parts, body) = request.into_parts();
+
+        PreparedRequest {
+            method: parts.method,
+            uri: parts.uri,
+            headers: parts.headers,
+            body,
+        }
+    }
+}
+
+impl From<PreparedRequest> for http::Request<Bytes> {
+    fn from(request: PreparedRequest) -> Self {
+        request.into_request()
+    }
+}
+
+impl fmt::Debug for PreparedRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut headers = self.headers.clone();
+
+        if headers.contains_key(AUTHORIZATION) {
+            headers.insert(AUTHORIZATION, http::HeaderValue::from_static("<redacted>"));
+        }
+
+        let uri = self.uri.to_string();
+        let uri = match uri.rsplit_once('/') {
+            Some((path, _)) => format!("{}/<redacted>", path),
+            None => uri,
+        };
+
+        f.debug_struct("PreparedRequest")
+            .field("method", &self.method)
+            .field("uri", &uri)
+            .field("headers", &headers)
+            .field("body", &format_args!("<{} bytes>", self.body.len()))
+            .finish()
+    }
+}
+
+/// Maps a response of APNs the same way [`Client::send`](super::Client::send)
+/// does: a `200 OK` to a [`Response`], anything else to an
+/// [`Error::ResponseError`] holding the reason from the body, if any.
+///
+/// ```
+/// # use a2::client::parse_response;
+/// # use a2::{Error, ErrorReason};
+/// let response = http::Response::builder()
+///     .status(410)
+///     .header("apns-id", "an-apns-id")
+///     .body(r#"{"reason":"Unregistered","timestamp":1508249865488}"#)
+///     .unwrap();
+///
+/// let Err(Error::ResponseError(response)) = parse_response(&response) else {
+///     panic!("not an error response");
+/// };
+/// assert_eq!(Some(ErrorReason::Unregistered), response.error.map(|error| error.reason));
+/// ```
+pub fn parse_response<B: AsRef<[u8]>>(response: &http::Response<B>) -> Result<Response, Error> {
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|s| s.to_str().ok())
+            .map(String::from)
+    };
+
+    let apns_id = header("apns-id");
+    let apns_unique_id = header("apns-unique-id");
+
+    match response.status() {
+        StatusCode::OK => Ok(Response {
+            apns_id,
+            apns_unique_id,
+            error: None,
+            code: response.status().as_u16(),
+        }),
+        status => Err(Error::ResponseError(Response {
+            apns_id,
+            apns_unique_id,
+            error: serde_json::from_slice(response.body().as_ref()).ok(),
+            code: status.as_u16(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::ErrorReason;
+
+    #[test]
+    fn test_request_round_trip() {
+        let request = http::Request::post("https://api.push.apple.com/3/device/a_test_id")
+            .header("apns-topic", "com.example.app")
+            .body(Bytes::from_static(b"{}"))
+            .unwrap();
+
+        let prepared = PreparedRequest::from(request);
+        assert_eq!(Method::POST, prepared.method);
+        assert_eq!("com.example.app", prepared.headers["apns-topic"]);
+
+        let request = prepared.clone().into_request();
+        assert_eq!(prepared.uri, *request.uri());
+        assert_eq!(prepared.body, *request.body());
+    }
+
+    #[test]
+    fn test_debug_hides_the_secrets() {
+        let request = http::Request::post("https://api.push.apple.com/3/device/a_test_id")
+            .header(AUTHORIZATION, "Bearer a.secret.token")
+            .body(Bytes::from_static(br#"{"aps":{"alert":"A secret message"}}"#))
+            .unwrap();
+
+        let debug = format!("{:?}", PreparedRequest::from(request));
+
+        assert!(!debug.contains("a.secret.token"));
+        assert!(!debug.contains("a_test_id"));
+        assert!(!debug.contains("A secret message"));
+        assert!(debug.contains("https://api.push.apple.com/3/device/<redacted>"));
+        assert!(debug.contains("<36 bytes>"));
+    }
+
+    #[test]
+    fn test_parse_responses() {
+        let ok = http::Response::builder()
+            .header("apns-id", "an-apns-id")
+            .header("apns-unique-id", "a-unique-id")
+            .body(Vec::new())
+            .unwrap();
+        let response = parse_response(&ok).unwrap();
+
+        assert_eq!(200, response.code);
+        assert_eq!(Some("an-apns-id".to_string()), response.apns_id);
+        assert_eq!(Some("a-unique-id".to_string()), response.apns_unique_id);
+        assert!(response.error.is_none());
+
+        let bad_request = http::Response::builder()
+            .status(400)
+            .body(r#"{"reason":"BadDeviceToken"}"#)
+            .unwrap();
+        let Err(Error::ResponseError(response)) = parse_response(&bad_request) else {
+            panic!("Expected a response error");
+        };
+
+        assert_eq!(400, response.code);
+        assert_eq!(
+            Some(ErrorReason::BadDeviceToken),
+            response.error.map(|error| error.reason)
+        );
+
+        let unparseable = http::Response::builder().status(502).body("Bad Gateway").unwrap();
+        let Err(Error::ResponseError(response)) = parse_response(&unparseable) else {
+            panic!("Expected a response error");
+        };
+
+        assert_eq!(502, response.code);
+        assert!(response.error.is_none());
+    }
+}
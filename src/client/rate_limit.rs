@@ -0,0 +1,432 @@
+//! Limiting the rate of notifications sent by the client
+
+use crate::error::Error;
+use parking_lot::Mutex;
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::time::{Duration, Instant};
+
+/// What to do with a notification over a rate limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum RateLimitAction {
+    /// Wait until the notification can be sent.
+    #[default]
+    Delay,
+    /// Fail right away with [`Error::DeviceRateLimited`].
+    Reject,
+}
+
+/// A limit on how many notifications one device token receives within a
+/// time window, enforced before the notifications reach APNs. Going over the
+/// limit Apple allows results in `429 TooManyRequests` responses.
+///
+/// Every token gets a token bucket holding `notifications`, refilled evenly
+/// over `window`. A device receives at most `notifications` at once, and
+/// `notifications` per `window` on average, without the double burst of fixed
+/// windows meeting at a boundary.
+///
+/// At most `max_devices` tokens are tracked. Past that, the least recently
+/// notified token is forgotten, which usually has a refilled bucket already.
+///
+/// ```
+/// # use a2::client::{DeviceRateLimit, RateLimitAction};
+/// # use std::time::Duration;
+/// let limit = DeviceRateLimit {
+///     action: RateLimitAction::Reject,
+///     ..DeviceRateLimit::new(5, Duration::from_secs(60))
+/// };
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceRateLimit {
+    /// The number of notifications allowed at once, and per window.
+    pub notifications: u32,
+    /// The time to refill the allowed notifications.
+    pub window: Duration,
+    /// What to do with notifications over the limit.
+    pub action: RateLimitAction,
+    /// The maximum number of device tokens tracked at once.
+    pub max_devices: usize,
+}
+
+impl DeviceRateLimit {
+    /// Allow `notifications` per `window` for every device token, delaying
+    /// the rest.
+    pub fn new(notifications: u32, window: Duration) -> Self {
+        Self {
+            notifications,
+            window,
+            action: RateLimitAction::Delay,
+            max_devices: 1_000_000,
+        }
+    }
+}
+
+/// A limit on the requests a [`Client`](super::Client) sends to APNs, as a
+/// token bucket: `rate` requests per second on average, with bursts of up to
+/// `burst` requests. Sending waits while the bucket is empty, see
+/// [`Client::rate_limit_wait`](super::Client::rate_limit_wait).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    /// The number of requests per second.
+    pub rate: f64,
+    /// The number of requests that can be sent at once after being idle.
+    pub burst: u32,
+}
+
+impl RateLimit {
+    /// Allow `rate` requests per second, in bursts of up to `burst`.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        Self { rate, burst }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative when requests are waiting for tokens they already reserved.
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Puts a reserved token back, if the request stops waiting for it.
+struct Reservation<'a>(Option<&'a Limiter>);
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(limiter) = self.0 {
+            limiter.bucket.lock().tokens += 1.0;
+        }
+    }
+}
+
+impl Limiter {
+    pub(crate) fn new(limit: RateLimit) -> Self {
+        let burst = f64::from(limit.burst.max(1));
+
+        Self {
+            rate: limit.rate.max(f64::MIN_POSITIVE),
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Waits for a token from the bucket.
+    pub(crate) async fn acquire(&self) {
+        let wait = self.reserve(Instant::now());
+
+        if !wait.is_zero() {
+            #[cfg(feature = "tracing")]
+            {
+                tracing::debug!("Limiter::acquire waiting {:?} for the rate limit", wait);
+            }
+
+            let mut reservation = Reservation(Some(self));
+            tokio::time::sleep(wait).await;
+            reservation.0 = None;
+        }
+    }
+
+    /// How long a request made now would wait.
+    pub(crate) fn wait(&self) -> Duration {
+        let now = Instant::now();
+        let mut bucket = self.bucket.lock();
+        self.refill(&mut bucket, now);
+
+        self.deficit(bucket.tokens - 1.0)
+    }
+
+    /// Takes a token, returning how long to wait until it is available.
+    fn reserve(&self, now: Instant) -> Duration {
+        let mut bucket = self.bucket.lock();
+        self.refill(&mut bucket, now);
+        bucket.tokens -= 1.0;
+
+        self.deficit(bucket.tokens)
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) {
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+        bucket.updated = bucket.updated.max(now);
+    }
+
+    fn deficit(&self, tokens: f64) -> Duration {
+        if tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64((-tokens / self.rate).min(f64::from(u32::MAX)))
+        }
+    }
+}
+
+/// Keeps a token bucket per device token. The tokens are kept as 64-bit
+/// hashes, so tracking a million of them takes some tens of megabytes.
+#[derive(Debug)]
+pub(crate) struct DeviceLimiter {
+    limit: DeviceRateLimit,
+    hasher: RandomState,
+    devices: Mutex<Devices>,
+}
+
+/// The buckets of the devices, and the order they were used in for
+/// forgetting the least recent one in constant time. Using a device pushes
+/// an entry to `order` instead of moving the existing one, so only the entry
+/// with the latest `used` of a device counts. The others are skipped when
+/// evicting and dropped when they make up half of `order`.
+#[derive(Debug, Default)]
+struct Devices {
+    buckets: HashMap<u64, DeviceBucket>,
+    order: VecDeque<(u64, u64)>,
+    uses: u64,
+}
+
+#[derive(Debug)]
+struct DeviceBucket {
+    tokens: f64,
+    updated: Instant,
+    used: u64,
+}
+
+impl Devices {
+    /// Records the use of the device, returning its bucket.
+    fn touch(&mut self, key: u64, capacity: f64, now: Instant) -> &mut DeviceBucket {
+        self.uses += 1;
+        let used = self.uses;
+
+        self.order.push_back((key, used));
+
+        if self.order.len() > 2 * self.buckets.len() + 1 {
+            let buckets = &self.buckets;
+            self.order
+                .retain(|(key, used)| buckets.get(key).map_or(false, |bucket| bucket.used == *used));
+        }
+
+        let bucket = self.buckets.entry(key).or_insert(DeviceBucket {
+            tokens: capacity,
+            updated: now,
+            used,
+        });
+        bucket.used = used;
+        bucket
+    }
+
+    /// Forgets the least recently used device.
+    fn evict(&mut self) {
+        while let Some((key, used)) = self.order.pop_front() {
+            if self.buckets.get(&key).map_or(false, |bucket| bucket.used == used) {
+                self.buckets.remove(&key);
+                return;
+            }
+        }
+    }
+}
+
+impl DeviceLimiter {
+    pub(crate) fn new(limit: DeviceRateLimit) -> Self {
+        Self {
+            limit,
+            hasher: RandomState::new(),
+            devices: Mutex::new(Devices::default()),
+        }
+    }
+
+    /// Waits until a notification can be sent to the device, or fails if
+    /// the limit says to reject it.
+    pub(crate) async fn acquire(&self, device_token: &str) -> Result<(), Error> {
+        let mut hasher = self.hasher.build_hasher();
+        device_token.hash(&mut hasher);
+        let key = hasher.finish();
+
+        loop {
+            let wait = match self.try_acquire(key, Instant::now()) {
+                None => return Ok(()),
+                Some(_) if self.limit.action == RateLimitAction::Reject => return Err(Error::DeviceRateLimited),
+                Some(wait) => wait,
+            };
+
+            #[cfg(feature = "tracing")]
+            {
+                tracing::debug!("DeviceLimiter::acquire delaying a notification by {:?}", wait);
+            }
+
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes a token from the bucket of the device, or returns the time
+    /// until one is available.
+    fn try_acquire(&self, key: u64, now: Instant) -> Option<Duration> {
+        let window = self.limit.window.max(Duration::from_millis(1));
+
+        if self.limit.notifications == 0 {
+            return Some(window);
+        }
+
+        let capacity = f64::from(self.limit.notifications);
+        let rate = capacity / window.as_secs_f64();
+
+        let mut devices = self.devices.lock();
+
+        if !devices.buckets.contains_key(&key) && devices.buckets.len() >= self.limit.max_devices.max(1) {
+            devices.evict();
+        }
+
+        let bucket = devices.touch(key, capacity, now);
+
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens = (bucket.tokens + elapsed.as_secs_f64() * rate).min(capacity);
+        bucket.updated = bucket.updated.max(now);
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = Limiter::new(RateLimit::new(10.0, 2));
+        let start = limiter.bucket.lock().updated;
+
+        assert_eq!(Duration::ZERO, limiter.reserve(start));
+        assert_eq!(Duration::ZERO, limiter.reserve(start));
+        assert_eq!(Duration::from_millis(100), limiter.reserve(start));
+        assert_eq!(Duration::from_millis(200), limiter.reserve(start));
+
+        assert_eq!(
+            Duration::from_millis(100),
+            limiter.reserve(start + Duration::from_millis(200))
+        );
+        assert_eq!(Duration::ZERO, limiter.reserve(start + Duration::from_secs(10)));
+        assert_eq!(1.0, limiter.bucket.lock().tokens);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_wait() {
+        let limiter = Limiter::new(RateLimit::new(20.0, 1));
+
+        assert_eq!(Duration::ZERO, limiter.wait());
+        limiter.acquire().await;
+        assert!(limiter.wait() > Duration::from_millis(10));
+
+        let start = Instant::now();
+        limiter.acquire().await;
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_wait_returns_the_token() {
+        let limiter = Limiter::new(RateLimit::new(1.0, 1));
+        limiter.acquire().await;
+
+        let waiting = tokio::time::timeout(Duration::from_millis(10), limiter.acquire()).await;
+        assert!(waiting.is_err());
+
+        assert!(limiter.wait() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_device_token_bucket() {
+        let limiter = DeviceLimiter::new(DeviceRateLimit::new(2, Duration::from_secs(8)));
+        let start = Instant::now();
+
+        assert_eq!(None, limiter.try_acquire(1, start));
+        assert_eq!(None, limiter.try_acquire(1, start + Duration::from_secs(1)));
+        assert_eq!(None, limiter.try_acquire(2, start + Duration::from_secs(1)));
+        assert_eq!(
+            Some(Duration::from_secs(2)),
+            limiter.try_acquire(1, start + Duration::from_secs(2))
+        );
+        assert_eq!(None, limiter.try_acquire(1, start + Duration::from_secs(4)));
+
+        // No second burst right after the first one, unlike fixed windows.
+        assert!(limiter.try_acquire(1, start + Duration::from_secs(5)).is_some());
+        assert_eq!(None, limiter.try_acquire(1, start + Duration::from_secs(20)));
+        assert_eq!(None, limiter.try_acquire(1, start + Duration::from_secs(20)));
+        assert!(limiter.try_acquire(1, start + Duration::from_secs(20)).is_some());
+    }
+
+    #[test]
+    fn test_least_recent_device_is_forgotten() {
+        let limiter = DeviceLimiter::new(DeviceRateLimit {
+            max_devices: 2,
+            ..DeviceRateLimit::new(1, Duration::from_secs(10))
+        });
+        let start = Instant::now();
+
+        assert_eq!(None, limiter.try_acquire(0, start));
+        assert_eq!(None, limiter.try_acquire(1, start));
+        assert!(limiter.try_acquire(0, start).is_some());
+        assert_eq!(None, limiter.try_acquire(2, start));
+
+        let devices = limiter.devices.lock();
+        assert_eq!(2, devices.buckets.len());
+        assert!(!devices.buckets.contains_key(&1));
+        drop(devices);
+
+        assert!(limiter.try_acquire(0, start).is_some());
+        assert!(limiter.try_acquire(2, start).is_some());
+    }
+
+    #[test]
+    fn test_eviction_order_stays_bounded() {
+        let limiter = DeviceLimiter::new(DeviceRateLimit {
+            max_devices: 100,
+            ..DeviceRateLimit::new(1, Duration::from_secs(10))
+        });
+        let start = Instant::now();
+
+        for key in 0..10_000 {
+            limiter.try_acquire(key, start);
+            limiter.try_acquire(key % 7, start);
+
+            let devices = limiter.devices.lock();
+            assert!(devices.buckets.len() <= 100);
+            assert!(devices.order.len() <= 2 * devices.buckets.len() + 1);
+        }
+
+        let devices = limiter.devices.lock();
+        assert_eq!(100, devices.buckets.len());
+        assert!((0..7).all(|key| devices.buckets.contains_key(&key)));
+    }
+
+    #[tokio::test]
+    async fn test_reject_over_the_limit() {
+        let limiter = DeviceLimiter::new(DeviceRateLimit {
+            action: RateLimitAction::Reject,
+            ..DeviceRateLimit::new(1, Duration::from_secs(10))
+        });
+
+        assert!(limiter.acquire("a").await.is_ok());
+        assert!(matches!(limiter.acquire("a").await, Err(Error::DeviceRateLimited)));
+        assert!(limiter.acquire("b").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delay_over_the_limit() {
+        let limiter = DeviceLimiter::new(DeviceRateLimit::new(1, Duration::from_millis(100)));
+
+        limiter.acquire("a").await.unwrap();
+
+        let start = Instant::now();
+        limiter.acquire("a").await.unwrap();
+        assert!(start.elapsed() > Duration::from_millis(1));
+    }
+}
@@ -0,0 +1,68 @@
+//! Keeping device tokens out of logs
+
+use ring::digest;
+use std::fmt::Write;
+
+/// How device tokens are shown in the tracing spans of the
+/// [`Client`](super::Client).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TokenRedaction {
+    /// The first 16 hex digits of the SHA-256 digest of the token. The same
+    /// token always gets the same digest, so the logs of one device can still
+    /// be correlated.
+    #[default]
+    Hashed,
+    /// The first eight characters of the token.
+    Truncated,
+    /// No device token at all.
+    Omitted,
+}
+
+impl TokenRedaction {
+    /// The device token as shown in logs, or `None` if omitted.
+    pub fn redact(&self, device_token: &str) -> Option<String> {
+        match self {
+            TokenRedaction::Hashed => {
+                let digest = digest::digest(&digest::SHA256, device_token.as_bytes());
+
+                Some(digest.as_ref()[..8].iter().fold(String::new(), |mut hex, b| {
+                    let _ = write!(hex, "{:02x}", b);
+                    hex
+                }))
+            }
+            TokenRedaction::Truncated => Some(truncate(device_token)),
+            TokenRedaction::Omitted => None,
+        }
+    }
+}
+
+/// The first eight characters of a device token, followed by an ellipsis.
+fn truncate(device_token: &str) -> String {
+    match device_token.char_indices().nth(8) {
+        Some((end, _)) => format!("{}…", &device_token[..end]),
+        None => device_token.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "a0b1c2d3e4f5a6b7c8d9e0f1a2b3c4d5e6f7a8b9c0d1e2f3a4b5c6d7e8f9a0b1";
+
+    #[test]
+    fn test_token_redaction() {
+        let hashed = TokenRedaction::Hashed.redact(TOKEN).unwrap();
+        assert_eq!(16, hashed.len());
+        assert_eq!(Some(hashed), TokenRedaction::Hashed.redact(TOKEN));
+        assert_ne!(
+            TokenRedaction::Hashed.redact(TOKEN),
+            TokenRedaction::Hashed.redact("other")
+        );
+
+        assert_eq!(Some("a0b1c2d3…".to_string()), TokenRedaction::Truncated.redact(TOKEN));
+        assert_eq!(Some("short".to_string()), TokenRedaction::Truncated.redact("short"));
+
+        assert_eq!(None, TokenRedaction::Omitted.redact(TOKEN));
+    }
+}
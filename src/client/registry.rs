@@ -0,0 +1,249 @@
+//! Routing notifications to the clients of many apps
+
+use super::{Client, ClientConfig, Credentials};
+use crate::error::Error;
+use crate::request::payload::PayloadLike;
+use crate::response::Response;
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Clients for many apps or teams, called tenants, sending every
+/// notification with the client of its tenant. Notifications are routed by
+/// their [`apns_topic`](crate::NotificationOptions::apns_topic) or an
+/// explicit tenant key.
+///
+/// Every tenant has a [`Client`] of its own, so connections are never shared
+/// between certificates or signing keys, which APNs rejects with
+/// [`UnrelatedKeyIdInToken`](crate::ErrorReason::UnrelatedKeyIdInToken).
+/// Tenants can be added and removed at runtime, and clones of the registry
+/// share the same tenants.
+///
+/// ```no_run
+/// # use a2::client::{ClientRegistry, Credentials};
+/// # use a2::{ClientConfig, DefaultNotificationBuilder, NotificationBuilder, NotificationOptions};
+/// # use std::fs::File;
+/// # async fn send() -> Result<(), a2::Error> {
+/// let registry = ClientRegistry::new();
+///
+/// let credentials = Credentials::token(File::open("app1.p8")?, "KEY_ID", "TEAM_ID")?;
+/// registry.insert_credentials("app1", ["com.example.app1"], credentials, ClientConfig::default())?;
+///
+/// let options = NotificationOptions {
+///     apns_topic: Some("com.example.app1"),
+///     ..Default::default()
+/// };
+/// let payload = DefaultNotificationBuilder::new().build("a_device_token", options);
+///
+/// registry.send(payload).await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ClientRegistry {
+    tenants: Arc<RwLock<Tenants>>,
+}
+
+#[derive(Debug, Default)]
+struct Tenants {
+    clients: HashMap<String, Client>,
+    topics: HashMap<String, String>,
+}
+
+impl ClientRegistry {
+    /// A registry without tenants.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a tenant sending with the client the notifications for the given
+    /// topics. Replaces the client and the topics of a tenant with the same
+    /// key, returning the replaced client. A topic of another tenant is moved
+    /// to this one.
+    pub fn insert<K, I, T>(&self, tenant: K, topics: I, client: Client) -> Option<Client>
+    where
+        K: Into<String>,
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let tenant = tenant.into();
+        let mut tenants = self.tenants.write();
+
+        tenants.topics.retain(|_, owner| *owner != tenant);
+
+        for topic in topics {
+            tenants.topics.insert(topic.into(), tenant.clone());
+        }
+
+        tenants.clients.insert(tenant, client)
+    }
+
+    /// Like [`ClientRegistry::insert`], creating the client of the tenant
+    /// with [`Client::with_credentials`].
+    pub fn insert_credentials<K, I, T>(
+        &self,
+        tenant: K,
+        topics: I,
+        credentials: Credentials,
+        config: ClientConfig,
+    ) -> Result<Option<Client>, Error>
+    where
+        K: Into<String>,
+        I: IntoIterator<Item = T>,
+        T: Into<String>,
+    {
+        let client = Client::with_credentials(credentials, config)?;
+
+        Ok(self.insert(tenant, topics, client))
+    }
+
+    /// Removes a tenant and its topics, returning its client. Notifications
+    /// in flight still finish, and [`Client::shutdown`] waits for them.
+    pub fn remove(&self, tenant: &str) -> Option<Client> {
+        let mut tenants = self.tenants.write();
+
+        tenants.topics.retain(|_, owner| owner != tenant);
+        tenants.clients.remove(tenant)
+    }
+
+    /// The client of the tenant.
+    pub fn get(&self, tenant: &str) -> Option<Client> {
+        self.tenants.read().clients.get(tenant).cloned()
+    }
+
+    /// The client of the tenant sending the notifications for the topic.
+    pub fn get_by_topic(&self, topic: &str) -> Option<Client> {
+        let tenants = self.tenants.read();
+
+        tenants
+            .topics
+            .get(topic)
+            .and_then(|tenant| tenants.clients.get(tenant))
+            .cloned()
+    }
+
+    /// The keys of all tenants.
+    pub fn tenants(&self) -> Vec<String> {
+        self.tenants.read().clients.keys().cloned().collect()
+    }
+
+    /// The number of tenants.
+    pub fn len(&self) -> usize {
+        self.tenants.read().clients.len()
+    }
+
+    /// True if there are no tenants.
+    pub fn is_empty(&self) -> bool {
+        self.tenants.read().clients.is_empty()
+    }
+
+    /// Sends a notification with the client of the tenant of its
+    /// `apns_topic`. Fails with [`Error::UnknownTenant`] if the notification
+    /// has no topic or no tenant sends for it.
+    pub async fn send<T: PayloadLike>(&self, payload: T) -> Result<Response, Error> {
+        let topic = payload.get_options().apns_topic.unwrap_or_default();
+
+        let Some(client) = self.get_by_topic(topic) else {
+            return Err(Error::UnknownTenant(topic.to_string()));
+        };
+
+        client.send(payload).await
+    }
+
+    /// Sends a notification with the client of the tenant. Fails with
+    /// [`Error::UnknownTenant`] if there is no such tenant.
+    pub async fn send_to<T: PayloadLike>(&self, tenant: &str, payload: T) -> Result<Response, Error> {
+        let Some(client) = self.get(tenant) else {
+            return Err(Error::UnknownTenant(tenant.to_string()));
+        };
+
+        client.send(payload).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Endpoint;
+
+    fn client() -> Client {
+        Client::builder()
+            .config(ClientConfig::new(Endpoint::Sandbox))
+            .build()
+            .unwrap()
+    }
+
+    fn same(a: Option<Client>, b: &Client) -> bool {
+        a.map_or(false, |a| Arc::ptr_eq(&a.shutdown, &b.shutdown))
+    }
+
+    #[test]
+    fn test_routing_by_tenant_and_topic() {
+        let registry = ClientRegistry::new();
+        let app1 = client();
+        let app2 = client();
+
+        assert!(registry
+            .insert("app1", ["com.example.app1", "com.example.app1.voip"], app1.clone())
+            .is_none());
+        assert!(registry.insert("app2", ["com.example.app2"], app2.clone()).is_none());
+
+        assert_eq!(2, registry.len());
+        assert!(same(registry.get("app1"), &app1));
+        assert!(same(registry.get_by_topic("com.example.app1.voip"), &app1));
+        assert!(same(registry.get_by_topic("com.example.app2"), &app2));
+        assert!(registry.get_by_topic("com.example.other").is_none());
+    }
+
+    #[test]
+    fn test_replacing_and_removing_tenants() {
+        let registry = ClientRegistry::new();
+        let app1 = client();
+        let replacement = client();
+
+        registry.insert("app1", ["com.example.app1", "com.example.shared"], app1.clone());
+        registry.insert("app2", ["com.example.app2"], client());
+
+        let replaced = registry.insert("app1", ["com.example.app1"], replacement.clone());
+        assert!(same(replaced, &app1));
+        assert!(same(registry.get_by_topic("com.example.app1"), &replacement));
+        assert!(registry.get_by_topic("com.example.shared").is_none());
+
+        registry.insert("app3", ["com.example.app2"], client());
+        assert!(same(
+            registry.get_by_topic("com.example.app2"),
+            &registry.get("app3").unwrap()
+        ));
+
+        assert!(same(registry.remove("app1"), &replacement));
+        assert!(registry.get_by_topic("com.example.app1").is_none());
+        assert!(registry.remove("app1").is_none());
+
+        let mut tenants = registry.tenants();
+        tenants.sort();
+        assert_eq!(vec!["app2", "app3"], tenants);
+    }
+
+    #[tokio::test]
+    async fn test_send_to_an_unknown_tenant() {
+        use crate::{DefaultNotificationBuilder, NotificationBuilder, NotificationOptions};
+
+        let registry = ClientRegistry::new();
+        registry.insert("app1", ["com.example.app1"], client());
+
+        let options = NotificationOptions {
+            apns_topic: Some("com.example.other"),
+            ..Default::default()
+        };
+        let payload = DefaultNotificationBuilder::new().build("a_test_id", options);
+
+        assert!(matches!(
+            registry.send(payload.clone()).await,
+            Err(Error::UnknownTenant(ref topic)) if topic == "com.example.other"
+        ));
+        assert!(matches!(
+            registry.send_to("app2", payload).await,
+            Err(Error::UnknownTenant(ref tenant)) if tenant == "app2"
+        ));
+    }
+}
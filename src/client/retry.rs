@@ -0,0 +1,227 @@
+//! Retrying failed requests with exponential backoff
+
+use crate::error::Error;
+use crate::response::ErrorReason;
+use ring::rand::{SecureRandom, SystemRandom};
+use std::fmt;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// When and how often the [`Client`](super::Client) retries a failed
+/// notification.
+///
+/// Every attempt uses the same `apns-id` header, so APNs can treat the
+/// retries as the same notification and drop duplicates. If the notification
+/// options don't define an id, the client generates one.
+///
+/// Once the [`CircuitBreaker`](super::CircuitBreaker) opens, the retries
+/// stop and sending fails with the error of the last attempt sent.
+///
+/// A response with a `4xx` status other than `429 Too Many Requests`, such as
+/// `BadDeviceToken` or `Unregistered`, is never retried, no matter what the
+/// predicate says. Sending the same request again would fail the same way.
+///
+/// ```
+/// # use a2::client::RetryPolicy;
+/// # use std::time::Duration;
+/// let policy = RetryPolicy {
+///     max_attempts: 5,
+///     base_backoff: Duration::from_millis(50),
+///     ..Default::default()
+/// };
+/// ```
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry, doubled for every following one.
+    pub base_backoff: Duration,
+    /// The upper limit for the delay between attempts.
+    pub max_backoff: Duration,
+    /// Pick a random delay between zero and the exponential backoff, so
+    /// clients failing at the same time don't retry at the same time.
+    pub jitter: bool,
+    /// Decides if a failure is worth retrying. Defaults to
+    /// [`RetryPolicy::is_transient`].
+    pub retry_if: Arc<dyn Fn(&Error) -> bool + Send + Sync>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            jitter: true,
+            retry_if: Arc::new(Self::is_transient),
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_backoff", &self.base_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    /// Use the given predicate to decide which failures to retry.
+    pub fn retry_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Error) -> bool + Send + Sync + 'static,
+    {
+        self.retry_if = Arc::new(predicate);
+        self
+    }
+
+    /// True for failures likely to succeed when trying again: connection
+    /// errors, timeouts and APNs being unavailable.
+    pub fn is_transient(error: &Error) -> bool {
+        match error {
+            Error::ConnectionError(_)
+            | Error::ClientError(_)
+            | Error::ConnectError(_)
+            | Error::Transport(_)
+            | Error::RequestTimeout(_) => true,
+            Error::ResponseError(response) => matches!(
+                response.error.as_ref().map(|body| &body.reason),
+                Some(ErrorReason::InternalServerError | ErrorReason::ServiceUnavailable | ErrorReason::Shutdown)
+            ),
+            _ => false,
+        }
+    }
+
+    /// True if the failed `attempt` should be tried again.
+    pub(crate) fn should_retry(&self, error: &Error, attempt: u32) -> bool {
+        if attempt >= self.max_attempts || matches!(error, Error::CircuitOpen) {
+            return false;
+        }
+
+        if let Error::ResponseError(ref response) = error {
+            if (400..500).contains(&response.code) && response.code != 429 {
+                return false;
+            }
+        }
+
+        (self.retry_if)(error)
+    }
+
+    /// The delay before trying again after the failed `attempt`.
+    pub(crate) fn backoff(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(31);
+        let backoff = self.base_backoff.saturating_mul(1 << exponent).min(self.max_backoff);
+
+        if self.jitter {
+            backoff.mul_f64(random_u64() as f64 / u64::MAX as f64)
+        } else {
+            backoff
+        }
+    }
+}
+
+/// A random version 4 UUID, for identifying a notification over retries.
+pub(crate) fn new_apns_id() -> String {
+    let mut bytes = [0u8; 16];
+    bytes[..8].copy_from_slice(&random_u64().to_be_bytes());
+    bytes[8..].copy_from_slice(&random_u64().to_be_bytes());
+
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+fn random_u64() -> u64 {
+    let mut bytes = [0u8; 8];
+    // The system random source failing is not a reason to fail sending, the
+    // value is only used for jitter and ids.
+    let _ = SystemRandom::new().fill(&mut bytes);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::{ErrorBody, Response};
+
+    fn response_error(code: u16, reason: ErrorReason) -> Error {
+        Error::ResponseError(Response {
+            error: Some(ErrorBody {
+                reason,
+                timestamp: None,
+            }),
+            apns_id: None,
+            apns_unique_id: None,
+            code,
+        })
+    }
+
+    #[test]
+    fn test_transient_errors_are_retried() {
+        let policy = RetryPolicy::default();
+
+        assert!(policy.should_retry(&Error::RequestTimeout(Duration::from_secs(20)), 1));
+        assert!(policy.should_retry(&response_error(500, ErrorReason::InternalServerError), 1));
+        assert!(policy.should_retry(&response_error(503, ErrorReason::ServiceUnavailable), 2));
+        assert!(!policy.should_retry(&response_error(503, ErrorReason::Shutdown), 3));
+        assert!(!policy.should_retry(&Error::QueueFull, 1));
+    }
+
+    #[test]
+    fn test_permanent_errors_are_never_retried() {
+        let policy = RetryPolicy::default().retry_if(|_| true);
+
+        assert!(!policy.should_retry(&response_error(400, ErrorReason::BadDeviceToken), 1));
+        assert!(!policy.should_retry(&response_error(410, ErrorReason::Unregistered), 1));
+        assert!(policy.should_retry(&response_error(429, ErrorReason::TooManyRequests), 1));
+        assert!(!policy.should_retry(&Error::CircuitOpen, 1));
+    }
+
+    #[test]
+    fn test_exponential_backoff() {
+        let policy = RetryPolicy {
+            base_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            jitter: false,
+            ..Default::default()
+        };
+
+        assert_eq!(Duration::from_millis(100), policy.backoff(1));
+        assert_eq!(Duration::from_millis(200), policy.backoff(2));
+        assert_eq!(Duration::from_millis(400), policy.backoff(3));
+        assert_eq!(Duration::from_millis(500), policy.backoff(4));
+        assert_eq!(Duration::from_millis(500), policy.backoff(100));
+    }
+
+    #[test]
+    fn test_backoff_with_jitter() {
+        let policy = RetryPolicy::default();
+
+        for attempt in 1..10 {
+            assert!(policy.backoff(attempt) <= policy.max_backoff);
+        }
+    }
+
+    #[test]
+    fn test_new_apns_id() {
+        let id = new_apns_id();
+
+        assert_eq!(36, id.len());
+        assert_eq!(Some('4'), id.chars().nth(14));
+        assert_ne!(id, new_apns_id());
+    }
+}
@@ -0,0 +1,127 @@
+//! Using the client as a `tower` service
+
+use super::pool::Reservation;
+use super::Client;
+use crate::error::Error;
+use crate::request::payload::PayloadLike;
+use crate::response::Response;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// A [`Client`] as a [`tower_service::Service`] sending notification
+/// payloads, to be composed with `tower` layers. Always available, as the
+/// connections to APNs already depend on `tower-service`.
+///
+/// The service is ready when one of the connections can take another
+/// request without queueing, so layers such as buffers and load shedding see
+/// the backpressure from APNs. Becoming ready reserves a stream on that
+/// connection for the next call, so clones of the service becoming ready at
+/// the same time don't queue in the client. Dropping a ready service releases
+/// the stream.
+///
+/// ```no_run
+/// # use a2::{Client, ClientConfig, DefaultNotificationBuilder, NotificationBuilder};
+/// # use a2::client::ClientService;
+/// # use a2::request::payload::Payload;
+/// # use tower_service::Service;
+/// # async fn send(client: Client) -> Result<(), a2::Error> {
+/// let mut service = ClientService::new(client);
+///
+/// std::future::poll_fn(|cx| Service::<Payload>::poll_ready(&mut service, cx)).await?;
+///
+/// let payload = DefaultNotificationBuilder::new().build("device-token", Default::default());
+/// let response = service.call(payload).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClientService {
+    client: Client,
+    waiting: Option<BoxFuture<()>>,
+    reservation: Option<Reservation>,
+}
+
+impl ClientService {
+    /// Wraps the client into a service.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            waiting: None,
+            reservation: None,
+        }
+    }
+
+    /// The wrapped client.
+    pub fn get_ref(&self) -> &Client {
+        &self.client
+    }
+
+    /// Unwraps the client.
+    pub fn into_inner(self) -> Client {
+        self.client
+    }
+}
+
+impl Clone for ClientService {
+    fn clone(&self) -> Self {
+        Self::new(self.client.clone())
+    }
+}
+
+impl fmt::Debug for ClientService {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientService")
+            .field("client", &self.client)
+            .field("waiting", &self.waiting.is_some())
+            .field("ready", &self.reservation.is_some())
+            .finish()
+    }
+}
+
+impl From<Client> for ClientService {
+    fn from(client: Client) -> Self {
+        Self::new(client)
+    }
+}
+
+impl<P> tower_service::Service<P> for ClientService
+where
+    P: PayloadLike + Send + 'static,
+{
+    type Response = Response;
+    type Error = Error;
+    type Future = BoxFuture<Result<Response, Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            if let Some(ref mut waiting) = self.waiting {
+                if waiting.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                self.waiting = None;
+            }
+
+            let pool = match self.client.pool {
+                Some(ref pool) if self.reservation.is_none() => pool.clone(),
+                _ => return Poll::Ready(Ok(())),
+            };
+
+            self.reservation = pool.try_reserve();
+
+            if self.reservation.is_none() {
+                self.waiting = Some(Box::pin(async move { pool.wait_for_capacity().await }));
+            }
+        }
+    }
+
+    fn call(&mut self, payload: P) -> Self::Future {
+        let client = self.client.clone();
+        let reservation = self.reservation.take();
+
+        Box::pin(async move { client.send_reserved(payload, reservation).await })
+    }
+}
@@ -0,0 +1,369 @@
+//! Client configuration read from configuration files or the environment
+
+use super::{ClientConfig, Credentials, DryRun, Endpoint, Proxy, RootCertificate};
+use crate::error::Error;
+use base64::prelude::*;
+use serde::Deserialize;
+use std::fmt;
+use std::path::PathBuf;
+
+/// The configuration of a [`Client`](super::Client) including its
+/// credentials, deserializable from any format supported by serde, e.g. TOML
+/// or JSON. Build the client with
+/// [`Client::from_config`](super::Client::from_config).
+///
+/// Options left out keep the defaults of [`ClientConfig`].
+///
+/// ```
+/// # use a2::client::ClientSettings;
+/// let settings: ClientSettings = serde_json::from_str(r#"{
+///     "endpoint": "sandbox",
+///     "request_timeout_secs": 10,
+///     "credentials": {
+///         "type": "token",
+///         "key": { "base64_env": "APNS_KEY" },
+///         "key_id": "KEY_ID",
+///         "team_id": "TEAM_ID"
+///     }
+/// }"#).unwrap();
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientSettings {
+    /// The endpoint as parsed by [`Endpoint::from_str`](std::str::FromStr),
+    /// defaults to production
+    #[serde(default)]
+    pub endpoint: Option<Endpoint>,
+    /// The timeout of the HTTP requests
+    #[serde(default)]
+    pub request_timeout_secs: Option<u64>,
+    /// The timeout for idle sockets being kept alive
+    #[serde(default)]
+    pub pool_idle_timeout_secs: Option<u64>,
+    #[serde(default)]
+    pub http2_keep_alive_interval_secs: Option<u64>,
+    #[serde(default)]
+    pub http2_keep_alive_while_idle: Option<bool>,
+    /// PEM files of certificate authorities to trust in addition to the
+    /// bundled web PKI roots
+    #[serde(default)]
+    pub root_certificates: Vec<SecretSource>,
+    /// A proxy URL as parsed by [`Proxy::from_str`](std::str::FromStr)
+    #[serde(default)]
+    pub proxy: Option<Proxy>,
+    /// The number of HTTP/2 connections to open
+    #[serde(default)]
+    pub connections: Option<usize>,
+    /// The number of concurrent requests allowed on a connection until the
+    /// server announces its own limit
+    #[serde(default)]
+    pub initial_concurrent_streams: Option<u32>,
+    /// The most concurrent requests allowed on a connection
+    #[serde(default)]
+    pub max_concurrent_streams: Option<u32>,
+    /// The number of requests allowed to wait for a free stream
+    #[serde(default)]
+    pub send_queue_capacity: Option<usize>,
+    /// Record the requests instead of sending them, e.g. in staging. Read
+    /// them with [`Client::dry_run`](super::Client::dry_run)
+    #[serde(default)]
+    pub dry_run: bool,
+    /// How the client authenticates with APNs
+    pub credentials: CredentialsSettings,
+}
+
+impl ClientSettings {
+    /// The [`ClientConfig`] with these settings, reading the root
+    /// certificates.
+    pub fn client_config(&self) -> Result<ClientConfig, Error> {
+        let defaults = ClientConfig::default();
+
+        let mut root_certificates = Vec::new();
+
+        for source in &self.root_certificates {
+            root_certificates.extend(RootCertificate::from_pem(&source.read()?)?);
+        }
+
+        Ok(ClientConfig {
+            endpoint: self.endpoint.clone().unwrap_or(defaults.endpoint),
+            request_timeout_secs: self.request_timeout_secs.or(defaults.request_timeout_secs),
+            pool_idle_timeout_secs: self.pool_idle_timeout_secs.or(defaults.pool_idle_timeout_secs),
+            http2_keep_alive_interval_secs: self
+                .http2_keep_alive_interval_secs
+                .or(defaults.http2_keep_alive_interval_secs),
+            http2_keep_alive_while_idle: self
+                .http2_keep_alive_while_idle
+                .unwrap_or(defaults.http2_keep_alive_while_idle),
+            root_certificates,
+            proxy: self.proxy.clone(),
+            connections: self.connections.unwrap_or(defaults.connections),
+            initial_concurrent_streams: self
+                .initial_concurrent_streams
+                .unwrap_or(defaults.initial_concurrent_streams),
+            max_concurrent_streams: self.max_concurrent_streams.unwrap_or(defaults.max_concurrent_streams),
+            send_queue_capacity: self.send_queue_capacity.unwrap_or(defaults.send_queue_capacity),
+            dry_run: self.dry_run.then(DryRun::new),
+            ..defaults
+        })
+    }
+}
+
+/// How a client from [`ClientSettings`] authenticates with APNs, tagged by
+/// `type`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum CredentialsSettings {
+    /// Token-based authentication with a `.p8` private key, like
+    /// [`Credentials::token`].
+    Token {
+        /// The PEM-formatted private key
+        key: SecretSource,
+        key_id: String,
+        team_id: String,
+    },
+    /// Certificate-based authentication with a `.p12` provider certificate,
+    /// like [`Credentials::certificate`].
+    Certificate {
+        /// The PKCS#12 file
+        certificate: SecretSource,
+        /// The password of the file, if any
+        #[serde(default)]
+        password: Option<SecretSource>,
+    },
+}
+
+impl CredentialsSettings {
+    /// Reads the credentials from their sources.
+    pub fn load(&self) -> Result<Credentials, Error> {
+        match self {
+            CredentialsSettings::Token { key, key_id, team_id } => {
+                Credentials::token(key.read()?.as_slice(), key_id.as_str(), team_id.as_str())
+            }
+            CredentialsSettings::Certificate { certificate, password } => {
+                let password = match password {
+                    Some(password) => password.read_password()?,
+                    None => String::new(),
+                };
+
+                Credentials::certificate(&mut certificate.read()?.as_slice(), &password)
+            }
+        }
+    }
+}
+
+/// Where to read a key, a certificate or a password from, e.g.
+/// `{ "file": "/etc/apns/key.p8" }` or `{ "base64_env": "APNS_KEY" }`.
+///
+/// A password read from a `File` loses a single trailing newline, `\n` or
+/// `\r\n`, as most editors end files with one. Passwords from the other
+/// sources are used as they are, including any whitespace.
+#[derive(Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SecretSource {
+    /// The contents of a file
+    File(PathBuf),
+    /// The value of an environment variable
+    Env(String),
+    /// The base64-encoded value of an environment variable, e.g. for a binary
+    /// `.p12` file
+    Base64Env(String),
+    /// An inline base64-encoded value
+    Base64(String),
+    /// An inline value
+    Value(String),
+}
+
+impl SecretSource {
+    /// Reads the value.
+    pub fn read(&self) -> Result<Vec<u8>, Error> {
+        match self {
+            SecretSource::File(path) => Ok(std::fs::read(path)?),
+            SecretSource::Env(name) => Ok(env(name)?.into_bytes()),
+            SecretSource::Base64Env(name) => decode(&env(name)?),
+            SecretSource::Base64(value) => decode(value),
+            SecretSource::Value(value) => Ok(value.clone().into_bytes()),
+        }
+    }
+
+    /// Reads a UTF-8 password, without the trailing newline of a file.
+    fn read_password(&self) -> Result<String, Error> {
+        let mut password = String::from_utf8(self.read()?)
+            .map_err(|_| Error::InvalidConfig("the password is not valid UTF-8".to_string()))?;
+
+        if let SecretSource::File(_) = self {
+            if password.ends_with('\n') {
+                password.pop();
+
+                if password.ends_with('\r') {
+                    password.pop();
+                }
+            }
+        }
+
+        Ok(password)
+    }
+}
+
+/// Shows where the value is read from, never an inline value.
+impl fmt::Debug for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::File(path) => f.debug_tuple("File").field(path).finish(),
+            SecretSource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            SecretSource::Base64Env(name) => f.debug_tuple("Base64Env").field(name).finish(),
+            SecretSource::Base64(_) => f.write_str("Base64(<redacted>)"),
+            SecretSource::Value(_) => f.write_str("Value(<redacted>)"),
+        }
+    }
+}
+
+fn env(name: &str) -> Result<String, Error> {
+    std::env::var(name).map_err(|_| Error::InvalidConfig(format!("the environment variable {} is not set", name)))
+}
+
+fn decode(value: &str) -> Result<Vec<u8>, Error> {
+    let value: String = value.split_whitespace().collect();
+
+    BASE64_STANDARD
+        .decode(value)
+        .map_err(|_| Error::InvalidConfig("the value is not valid base64".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_settings() {
+        let settings: ClientSettings = serde_json::from_str(
+            r#"{
+                "endpoint": "http://127.0.0.1:8080",
+                "request_timeout_secs": 5,
+                "connections": 4,
+                "proxy": "socks5://proxy.example.com:1080",
+                "root_certificates": [{ "file": "test_cert/ca.crt" }],
+                "credentials": {
+                    "type": "certificate",
+                    "certificate": { "base64_env": "APNS_CERTIFICATE" },
+                    "password": { "env": "APNS_PASSWORD" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let config = settings.client_config().unwrap();
+
+        assert_eq!("http://127.0.0.1:8080".parse::<Endpoint>().unwrap(), config.endpoint);
+        assert_eq!(Some(5), config.request_timeout_secs);
+        assert_eq!(4, config.connections);
+        assert_eq!(1, config.root_certificates.len());
+        assert_eq!(Some(1080), config.proxy.map(|proxy| proxy.port));
+        assert_eq!(
+            ClientConfig::default().max_concurrent_streams,
+            config.max_concurrent_streams
+        );
+
+        assert!(matches!(
+            settings.credentials,
+            CredentialsSettings::Certificate {
+                password: Some(SecretSource::Env(_)),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_deserialize_defaults_and_unknown_fields() {
+        let settings: ClientSettings = serde_json::from_str(
+            r#"{ "credentials": { "type": "token", "key": { "file": "key.p8" }, "key_id": "a", "team_id": "b" } }"#,
+        )
+        .unwrap();
+
+        assert_eq!(Endpoint::Production, settings.client_config().unwrap().endpoint);
+        assert!(settings.client_config().unwrap().dry_run.is_none());
+
+        let unknown = serde_json::from_str::<ClientSettings>(
+            r#"{ "timeout": 5, "credentials": { "type": "token", "key": { "file": "key.p8" }, "key_id": "a", "team_id": "b" } }"#,
+        );
+        assert!(unknown.is_err());
+
+        let invalid_endpoint = serde_json::from_str::<ClientSettings>(
+            r#"{ "endpoint": "ftp://example.com", "credentials": { "type": "token", "key": { "file": "key.p8" }, "key_id": "a", "team_id": "b" } }"#,
+        );
+        assert!(invalid_endpoint.is_err());
+    }
+
+    #[test]
+    fn test_read_sources() {
+        std::env::set_var("A2_TEST_SOURCE_VALUE", "secret");
+        std::env::set_var("A2_TEST_SOURCE_BASE64", "c2VjcmV0");
+
+        assert_eq!(b"secret".to_vec(), SecretSource::Value("secret".into()).read().unwrap());
+        assert_eq!(
+            b"secret".to_vec(),
+            SecretSource::Base64("c2Vj\ncmV0".into()).read().unwrap()
+        );
+        assert_eq!(
+            b"secret".to_vec(),
+            SecretSource::Env("A2_TEST_SOURCE_VALUE".into()).read().unwrap()
+        );
+        assert_eq!(
+            b"secret".to_vec(),
+            SecretSource::Base64Env("A2_TEST_SOURCE_BASE64".into()).read().unwrap()
+        );
+        assert!(SecretSource::File("test_cert/ca.crt".into()).read().is_ok());
+
+        assert!(matches!(
+            SecretSource::Env("A2_TEST_SOURCE_MISSING".into()).read(),
+            Err(Error::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            SecretSource::Base64("not base64!".into()).read(),
+            Err(Error::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_read_passwords() {
+        let path = std::env::temp_dir().join(format!("a2-test-password-{}", std::process::id()));
+        let file = || SecretSource::File(path.clone());
+
+        std::fs::write(&path, "secret \n\n").unwrap();
+        assert_eq!("secret \n", file().read_password().unwrap());
+
+        std::fs::write(&path, "secret\r\n").unwrap();
+        assert_eq!("secret", file().read_password().unwrap());
+
+        std::fs::write(&path, "secret ").unwrap();
+        assert_eq!("secret ", file().read_password().unwrap());
+
+        assert_eq!(
+            "secret \n",
+            SecretSource::Value("secret \n".into()).read_password().unwrap()
+        );
+        assert!(matches!(
+            SecretSource::Base64("/w==".into()).read_password(),
+            Err(Error::InvalidConfig(_))
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_debug_hides_inline_values() {
+        let debug = format!(
+            "{:?}",
+            [
+                SecretSource::Value("secret".into()),
+                SecretSource::Base64("c2VjcmV0".into())
+            ]
+        );
+
+        assert!(!debug.contains("secret"));
+        assert!(!debug.contains("c2VjcmV0"));
+        assert_eq!(
+            "Env(\"APNS_KEY\")",
+            format!("{:?}", SecretSource::Env("APNS_KEY".into()))
+        );
+    }
+}
@@ -0,0 +1,192 @@
+//! Draining the requests in flight before closing the connections
+
+use crate::error::Error;
+use parking_lot::Mutex;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// How many notifications were still in flight when
+/// [`Client::shutdown`](super::Client::shutdown) was called, and what
+/// happened to them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Notifications getting a result from APNs before the deadline.
+    pub completed: usize,
+    /// Notifications still waiting for a result at the deadline. They fail
+    /// with [`Error::Shutdown`] and might or might not have been delivered.
+    pub abandoned: usize,
+}
+
+impl ShutdownSummary {
+    /// True if no notification was abandoned.
+    pub fn is_clean(&self) -> bool {
+        self.abandoned == 0
+    }
+}
+
+/// Counts the notifications in flight, shared by all clones of the client.
+#[derive(Debug, Default)]
+pub(crate) struct Shutdown {
+    state: Mutex<State>,
+    abandoned: AtomicBool,
+    idle: Notify,
+    abandon: Notify,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    closed: bool,
+    in_flight: usize,
+}
+
+/// Counts a notification in flight until dropped.
+pub(crate) struct Guard<'a> {
+    shutdown: &'a Shutdown,
+}
+
+impl Shutdown {
+    /// Counts a notification in flight, or fails if shutting down.
+    pub(crate) fn enter(&self) -> Result<Guard<'_>, Error> {
+        let mut state = self.state.lock();
+
+        if state.closed {
+            return Err(Error::Shutdown);
+        }
+
+        state.in_flight += 1;
+
+        Ok(Guard { shutdown: self })
+    }
+
+    /// Runs the sending future until done, or until abandoned by a shutdown
+    /// reaching its deadline.
+    pub(crate) async fn run<F, T>(&self, future: F) -> Result<T, Error>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        let abandoned = self.abandon.notified();
+
+        if self.abandoned.load(Ordering::Acquire) {
+            return Err(Error::Shutdown);
+        }
+
+        futures_util::pin_mut!(future);
+        futures_util::pin_mut!(abandoned);
+
+        match futures_util::future::select(future, abandoned).await {
+            futures_util::future::Either::Left((result, _)) => result,
+            futures_util::future::Either::Right(_) => Err(Error::Shutdown),
+        }
+    }
+
+    /// Stops accepting notifications and waits until the ones in flight are
+    /// done, abandoning those still in flight after the deadline.
+    pub(crate) async fn drain(&self, deadline: Duration) -> ShutdownSummary {
+        let started = {
+            let mut state = self.state.lock();
+            state.closed = true;
+            state.in_flight
+        };
+
+        let waiting = async {
+            loop {
+                let idle = self.idle.notified();
+
+                if self.state.lock().in_flight == 0 {
+                    break;
+                }
+
+                idle.await;
+            }
+        };
+
+        let abandoned = match tokio::time::timeout(deadline, waiting).await {
+            Ok(()) => 0,
+            Err(_) => {
+                self.abandoned.store(true, Ordering::Release);
+                self.abandon.notify_waiters();
+                self.state.lock().in_flight.min(started)
+            }
+        };
+
+        ShutdownSummary {
+            completed: started - abandoned,
+            abandoned,
+        }
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        let mut state = self.shutdown.state.lock();
+        state.in_flight -= 1;
+
+        if state.closed && state.in_flight == 0 {
+            drop(state);
+            self.shutdown.idle.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn test_drain_waits_for_the_requests_in_flight() {
+        let shutdown = Arc::new(Shutdown::default());
+
+        let sending = shutdown.clone();
+        let request = tokio::spawn(async move {
+            let _guard = sending.enter().unwrap();
+            sending
+                .run(async {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok(())
+                })
+                .await
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let summary = shutdown.drain(Duration::from_secs(5)).await;
+
+        assert_eq!(
+            ShutdownSummary {
+                completed: 1,
+                abandoned: 0
+            },
+            summary
+        );
+        assert!(request.await.unwrap().is_ok());
+        assert!(matches!(shutdown.enter(), Err(Error::Shutdown)));
+    }
+
+    #[tokio::test]
+    async fn test_drain_abandons_the_requests_after_the_deadline() {
+        let shutdown = Arc::new(Shutdown::default());
+
+        let sending = shutdown.clone();
+        let request = tokio::spawn(async move {
+            let _guard = sending.enter().unwrap();
+            sending.run(futures_util::future::pending::<Result<(), Error>>()).await
+        });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        let summary = shutdown.drain(Duration::from_millis(20)).await;
+
+        assert_eq!(
+            ShutdownSummary {
+                completed: 0,
+                abandoned: 1
+            },
+            summary
+        );
+        assert!(!summary.is_clean());
+        assert!(matches!(request.await.unwrap(), Err(Error::Shutdown)));
+    }
+}
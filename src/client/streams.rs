@@ -0,0 +1,627 @@
+//! Following the concurrent streams a server allows on a connection, and the
+//! round trips of its HTTP/2 `SETTINGS` and `PING` frames
+
+use hyper::rt::{Read, ReadBuf, ReadBufCursor, Write};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::Notify;
+
+/// The HTTP/2 `SETTINGS` and `PING` frame types, their `ACK` flag and the
+/// `SETTINGS_MAX_CONCURRENT_STREAMS` parameter.
+const SETTINGS: u8 = 0x4;
+const PING: u8 = 0x6;
+const ACK: u8 = 0x1;
+const MAX_CONCURRENT_STREAMS: u16 = 0x3;
+
+/// The length of the connection preface a client sends before its first
+/// frame.
+const PREFACE_LEN: usize = 24;
+
+/// The value of a peer setting not received yet.
+const UNKNOWN: usize = usize::MAX;
+
+/// The `SETTINGS_MAX_CONCURRENT_STREAMS` of a server leaving it out of its
+/// first `SETTINGS`, which means no limit.
+const UNLIMITED: u32 = u32::MAX;
+
+/// The most bytes copied from the connection per read.
+const MAX_READ: usize = 16 * 1024;
+
+/// The most frames waiting for an acknowledgement, older ones are
+/// forgotten.
+const MAX_UNACKED: usize = 16;
+
+/// The number of requests allowed at once on a connection: `initial` until
+/// the server announces its `SETTINGS_MAX_CONCURRENT_STREAMS`, then the
+/// announced value, at most `max`. Follows later changes of the setting.
+#[derive(Debug)]
+pub(crate) struct StreamLimit {
+    initial: usize,
+    max: usize,
+    peer: AtomicUsize,
+    in_use: AtomicUsize,
+    changed: Notify,
+}
+
+/// A stream of a [`StreamLimit`], released when dropped.
+#[derive(Debug)]
+pub(crate) struct StreamPermit {
+    limit: Arc<StreamLimit>,
+}
+
+impl StreamLimit {
+    pub(crate) fn new(initial: usize, max: usize) -> Self {
+        Self {
+            initial,
+            max,
+            peer: AtomicUsize::new(UNKNOWN),
+            in_use: AtomicUsize::new(0),
+            changed: Notify::new(),
+        }
+    }
+
+    /// The number of requests allowed at once right now.
+    pub(crate) fn limit(&self) -> usize {
+        match self.peer.load(Ordering::Acquire) {
+            UNKNOWN => self.initial,
+            peer => peer.min(self.max),
+        }
+    }
+
+    /// The number of streams taken right now.
+    pub(crate) fn in_use(&self) -> usize {
+        self.in_use.load(Ordering::Acquire)
+    }
+
+    /// The number of streams available right now.
+    pub(crate) fn available(&self) -> usize {
+        self.limit().saturating_sub(self.in_use())
+    }
+
+    /// Takes a stream if one is available.
+    pub(crate) fn try_acquire(self: &Arc<Self>) -> Option<StreamPermit> {
+        self.in_use
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |in_use| {
+                (in_use < self.limit()).then(|| in_use + 1)
+            })
+            .ok()
+            .map(|_| StreamPermit { limit: self.clone() })
+    }
+
+    /// Waits for a stream.
+    pub(crate) async fn acquire(self: &Arc<Self>) -> StreamPermit {
+        loop {
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+
+            if let Some(permit) = self.try_acquire() {
+                return permit;
+            }
+
+            changed.await;
+        }
+    }
+
+    /// Records the `SETTINGS_MAX_CONCURRENT_STREAMS` of the server.
+    fn set_peer(&self, max_streams: u32) {
+        let max_streams = usize::try_from(max_streams).unwrap_or(UNKNOWN - 1);
+
+        if self.peer.swap(max_streams, Ordering::AcqRel) != max_streams {
+            self.changed.notify_waiters();
+        }
+    }
+}
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.limit.in_use.fetch_sub(1, Ordering::AcqRel);
+        self.limit.changed.notify_waiters();
+    }
+}
+
+/// The round-trip times of a connection: the first one from the `SETTINGS`
+/// the client sends when opening the connection to the server's `ACK`, the
+/// later ones from the keep-alive `PING` frames to their `ACK`.
+#[derive(Debug, Default)]
+pub(crate) struct RoundTrips {
+    state: Mutex<RoundTripState>,
+    closed: AtomicBool,
+    measured: Notify,
+}
+
+#[derive(Debug, Default)]
+struct RoundTripState {
+    settings: VecDeque<Instant>,
+    pings: VecDeque<(Vec<u8>, Instant)>,
+    last: Option<Duration>,
+}
+
+impl RoundTrips {
+    /// The last round-trip time measured.
+    pub(crate) fn last(&self) -> Option<Duration> {
+        self.state.lock().last
+    }
+
+    /// Waits for the first round-trip time, or returns `None` if the
+    /// connection closes before.
+    pub(crate) async fn measured(&self) -> Option<Duration> {
+        loop {
+            let measured = self.measured.notified();
+            tokio::pin!(measured);
+            measured.as_mut().enable();
+
+            if let Some(rtt) = self.last() {
+                return Some(rtt);
+            }
+
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+
+            measured.await;
+        }
+    }
+
+    /// Records the connection closing, waking up the tasks waiting for a
+    /// round-trip time.
+    pub(crate) fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.measured.notify_waiters();
+    }
+
+    fn settings_sent(&self, now: Instant) {
+        let mut state = self.state.lock();
+
+        if state.settings.len() == MAX_UNACKED {
+            state.settings.pop_front();
+        }
+
+        state.settings.push_back(now);
+    }
+
+    fn settings_acked(&self, now: Instant) {
+        let mut state = self.state.lock();
+
+        if let Some(sent) = state.settings.pop_front() {
+            self.record(&mut state, now - sent);
+        }
+    }
+
+    fn ping_sent(&self, opaque: Vec<u8>, now: Instant) {
+        let mut state = self.state.lock();
+
+        if state.pings.len() == MAX_UNACKED {
+            state.pings.pop_front();
+        }
+
+        state.pings.push_back((opaque, now));
+    }
+
+    fn ping_acked(&self, opaque: &[u8], now: Instant) {
+        let mut state = self.state.lock();
+
+        if let Some(position) = state.pings.iter().position(|(sent, _)| sent == opaque) {
+            if let Some((_, sent)) = state.pings.remove(position) {
+                self.record(&mut state, now - sent);
+            }
+        }
+    }
+
+    fn record(&self, state: &mut RoundTripState, rtt: Duration) {
+        state.last = Some(rtt);
+        self.measured.notify_waiters();
+    }
+}
+
+/// A connection passing the frames sent and received through a [`Watch`].
+/// Hyper doesn't expose the settings of the server, nor the round trips of
+/// its keep-alive pings.
+#[derive(Debug)]
+pub(crate) struct Stream<T> {
+    inner: T,
+    watch: Watch,
+    buffer: Vec<u8>,
+}
+
+impl<T> Stream<T> {
+    pub(crate) fn new(inner: T, limit: Arc<StreamLimit>, round_trips: Arc<RoundTrips>) -> Self {
+        Self {
+            inner,
+            watch: Watch::new(limit, round_trips),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<T: Read + Unpin> Read for Stream<T> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, mut buf: ReadBufCursor<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let len = buf.remaining().min(MAX_READ);
+
+        if this.buffer.len() < len {
+            this.buffer.resize(len, 0);
+        }
+
+        let mut read = ReadBuf::new(&mut this.buffer[..len]);
+
+        match Pin::new(&mut this.inner).poll_read(cx, read.unfilled()) {
+            Poll::Ready(Ok(())) => {
+                let received = read.filled();
+                this.watch.received(received, Instant::now());
+                buf.put_slice(received);
+                Poll::Ready(Ok(()))
+            }
+            other => other,
+        }
+    }
+}
+
+impl<T: Write + Unpin> Write for Stream<T> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write(cx, buf);
+
+        if let Poll::Ready(Ok(len)) = written {
+            this.watch.sent(&buf[..len], Instant::now());
+        }
+
+        written
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+
+        if let Poll::Ready(Ok(mut len)) = written {
+            let now = Instant::now();
+
+            for buf in bufs {
+                let take = len.min(buf.len());
+                this.watch.sent(&buf[..take], now);
+                len -= take;
+
+                if len == 0 {
+                    break;
+                }
+            }
+        }
+
+        written
+    }
+}
+
+/// Follows the `SETTINGS` and `PING` frames of a connection, updating the
+/// stream limit and the round-trip times.
+#[derive(Debug)]
+struct Watch {
+    limit: Arc<StreamLimit>,
+    round_trips: Arc<RoundTrips>,
+    received: FrameReader,
+    sent: FrameReader,
+    settings_received: bool,
+}
+
+impl Watch {
+    fn new(limit: Arc<StreamLimit>, round_trips: Arc<RoundTrips>) -> Self {
+        Self {
+            limit,
+            round_trips,
+            received: FrameReader::default(),
+            sent: FrameReader::after_preface(),
+            settings_received: false,
+        }
+    }
+
+    /// Reads the bytes the server sent. A first `SETTINGS` without
+    /// `SETTINGS_MAX_CONCURRENT_STREAMS` means no limit, later ones keep the
+    /// current value.
+    fn received(&mut self, data: &[u8], now: Instant) {
+        for frame in self.received.read(data) {
+            match (frame.kind, frame.flags & ACK != 0) {
+                (SETTINGS, false) => {
+                    let mut max_streams = (!self.settings_received).then(|| UNLIMITED);
+                    self.settings_received = true;
+
+                    for parameter in frame.payload.chunks_exact(6) {
+                        if u16::from_be_bytes([parameter[0], parameter[1]]) == MAX_CONCURRENT_STREAMS {
+                            max_streams = Some(u32::from_be_bytes([
+                                parameter[2],
+                                parameter[3],
+                                parameter[4],
+                                parameter[5],
+                            ]));
+                        }
+                    }
+
+                    if let Some(max_streams) = max_streams {
+                        self.limit.set_peer(max_streams);
+                    }
+                }
+                (SETTINGS, true) => self.round_trips.settings_acked(now),
+                (PING, true) => self.round_trips.ping_acked(&frame.payload, now),
+                _ => {}
+            }
+        }
+    }
+
+    /// Reads the bytes the client sent. The `ACK` of the server's frames
+    /// are not round trips of the client.
+    fn sent(&mut self, data: &[u8], now: Instant) {
+        for frame in self.sent.read(data) {
+            match (frame.kind, frame.flags & ACK != 0) {
+                (SETTINGS, false) => self.round_trips.settings_sent(now),
+                (PING, false) => self.round_trips.ping_sent(frame.payload, now),
+                _ => {}
+            }
+        }
+    }
+}
+
+/// A `SETTINGS` or `PING` frame of the connection.
+#[derive(Debug)]
+struct Frame {
+    kind: u8,
+    flags: u8,
+    payload: Vec<u8>,
+}
+
+/// Splits the bytes sent one way into HTTP/2 frames, keeping the
+/// `SETTINGS` and `PING` frames only. Both ends start with a frame, after
+/// the preface on the client side.
+#[derive(Debug, Default)]
+struct FrameReader {
+    preface_left: usize,
+    header: Vec<u8>,
+    payload_left: usize,
+    frame: Option<Frame>,
+}
+
+impl FrameReader {
+    fn after_preface() -> Self {
+        Self {
+            preface_left: PREFACE_LEN,
+            ..Self::default()
+        }
+    }
+
+    /// Reads the next bytes, returning the frames completed in them.
+    fn read(&mut self, mut data: &[u8]) -> Vec<Frame> {
+        let mut frames = Vec::new();
+
+        let skip = self.preface_left.min(data.len());
+        self.preface_left -= skip;
+        data = &data[skip..];
+
+        while !data.is_empty() {
+            if self.payload_left == 0 && self.frame.is_none() {
+                let take = (9 - self.header.len()).min(data.len());
+                self.header.extend_from_slice(&data[..take]);
+                data = &data[take..];
+
+                if self.header.len() < 9 {
+                    break;
+                }
+
+                let length = u32::from_be_bytes([0, self.header[0], self.header[1], self.header[2]]) as usize;
+                let kind = self.header[3];
+                let stream_id = u32::from_be_bytes([self.header[5], self.header[6], self.header[7], self.header[8]]);
+
+                if (kind == SETTINGS || kind == PING) && stream_id & 0x7fff_ffff == 0 {
+                    self.frame = Some(Frame {
+                        kind,
+                        flags: self.header[4],
+                        payload: Vec::with_capacity(length),
+                    });
+                }
+
+                self.payload_left = length;
+                self.header.clear();
+            }
+
+            let take = self.payload_left.min(data.len());
+
+            if let Some(ref mut frame) = self.frame {
+                frame.payload.extend_from_slice(&data[..take]);
+            }
+
+            self.payload_left -= take;
+            data = &data[take..];
+
+            if self.payload_left == 0 {
+                frames.extend(self.frame.take());
+            }
+        }
+
+        frames
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes()[1..].to_vec();
+        frame.extend([kind, flags]);
+        frame.extend(stream_id.to_be_bytes());
+        frame.extend(payload);
+        frame
+    }
+
+    fn settings(parameters: &[(u16, u32)]) -> Vec<u8> {
+        let payload: Vec<u8> = parameters
+            .iter()
+            .flat_map(|(id, value)| id.to_be_bytes().into_iter().chain(value.to_be_bytes()))
+            .collect();
+
+        frame(SETTINGS, 0, 0, &payload)
+    }
+
+    fn watch() -> Watch {
+        Watch::new(Arc::new(StreamLimit::new(1, 2000)), Arc::new(RoundTrips::default()))
+    }
+
+    #[test]
+    fn test_reads_max_concurrent_streams() {
+        let mut watch = watch();
+        let now = Instant::now();
+
+        let mut received = settings(&[(0x4, 65535), (MAX_CONCURRENT_STREAMS, 5)]);
+        received.extend(frame(SETTINGS, ACK, 0, &[]));
+        received.extend(frame(0x1, 0x4, 1, &[0; 20]));
+        watch.received(&received, now);
+        assert_eq!(5, watch.limit.limit());
+
+        watch.received(&frame(0x0, 0x1, 1, &[0; 100]), now);
+        assert_eq!(5, watch.limit.limit());
+    }
+
+    #[test]
+    fn test_reads_frames_split_over_reads() {
+        let mut frames = FrameReader::default();
+
+        let mut received = frame(0x0, 0, 1, &[0; 30]);
+        received.extend(settings(&[(MAX_CONCURRENT_STREAMS, 1000)]));
+        received.extend(frame(PING, ACK, 0, &[7; 8]));
+
+        let read: Vec<(u8, u8, usize)> = received
+            .chunks(4)
+            .flat_map(|chunk| frames.read(chunk))
+            .map(|frame| (frame.kind, frame.flags, frame.payload.len()))
+            .collect();
+
+        assert_eq!(vec![(SETTINGS, 0, 6), (PING, ACK, 8)], read);
+    }
+
+    #[test]
+    fn test_first_settings_without_max_concurrent_streams() {
+        let mut watch = watch();
+        let now = Instant::now();
+
+        watch.received(&settings(&[(0x4, 65535)]), now);
+        assert_eq!(2000, watch.limit.limit());
+
+        watch.received(&settings(&[(MAX_CONCURRENT_STREAMS, 10)]), now);
+        watch.received(&settings(&[(0x4, 65535)]), now);
+        assert_eq!(10, watch.limit.limit());
+    }
+
+    #[test]
+    fn test_ignores_acks_and_other_frames() {
+        let mut watch = watch();
+
+        let mut ack = settings(&[(MAX_CONCURRENT_STREAMS, 5)]);
+        ack[4] = ACK;
+        let mut data = frame(0x0, 0, 1, &settings(&[(MAX_CONCURRENT_STREAMS, 7)]));
+        data.extend(ack);
+        data.extend(frame(PING, 0, 0, &[1; 8]));
+        watch.received(&data, Instant::now());
+
+        assert_eq!(1, watch.limit.limit());
+        assert_eq!(None, watch.round_trips.last());
+    }
+
+    #[test]
+    fn test_round_trips() {
+        let mut watch = watch();
+        let start = Instant::now();
+        let at = |millis| start + Duration::from_millis(millis);
+
+        let mut sent = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n".to_vec();
+        sent.extend(settings(&[(0x2, 0)]));
+        watch.sent(&sent[..10], at(0));
+        watch.sent(&sent[10..], at(0));
+        assert_eq!(None, watch.round_trips.last());
+
+        // The server's own settings and pings don't count.
+        watch.received(&settings(&[]), at(5));
+        watch.received(&frame(PING, 0, 0, &[9; 8]), at(5));
+        watch.sent(&frame(SETTINGS, ACK, 0, &[]), at(6));
+        watch.sent(&frame(PING, ACK, 0, &[9; 8]), at(6));
+        assert_eq!(None, watch.round_trips.last());
+
+        watch.received(&frame(SETTINGS, ACK, 0, &[]), at(20));
+        assert_eq!(Some(Duration::from_millis(20)), watch.round_trips.last());
+
+        watch.sent(&frame(PING, 0, 0, &[1; 8]), at(100));
+        watch.sent(&frame(PING, 0, 0, &[2; 8]), at(110));
+        watch.received(&frame(PING, ACK, 0, &[2; 8]), at(140));
+        assert_eq!(Some(Duration::from_millis(30)), watch.round_trips.last());
+
+        watch.received(&frame(PING, ACK, 0, &[3; 8]), at(150));
+        assert_eq!(Some(Duration::from_millis(30)), watch.round_trips.last());
+    }
+
+    #[tokio::test]
+    async fn test_measured_round_trip() {
+        let round_trips = Arc::new(RoundTrips::default());
+
+        let waiting = tokio::spawn({
+            let round_trips = round_trips.clone();
+            async move { round_trips.measured().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        let now = Instant::now();
+        round_trips.settings_sent(now);
+        round_trips.settings_acked(now + Duration::from_millis(15));
+        assert_eq!(Some(Duration::from_millis(15)), waiting.await.unwrap());
+
+        let closed = RoundTrips::default();
+        closed.close();
+        assert_eq!(None, closed.measured().await);
+    }
+
+    #[tokio::test]
+    async fn test_stream_limit_follows_the_server() {
+        let limit = Arc::new(StreamLimit::new(1, 10));
+
+        let first = limit.try_acquire().unwrap();
+        assert!(limit.try_acquire().is_none());
+
+        let waiting = tokio::spawn({
+            let limit = limit.clone();
+            async move { limit.acquire().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiting.is_finished());
+
+        limit.set_peer(1000);
+        assert_eq!(10, limit.limit());
+        let second = waiting.await.unwrap();
+        assert_eq!(8, limit.available());
+        assert_eq!(2, limit.in_use());
+
+        limit.set_peer(1);
+        assert_eq!(0, limit.available());
+        assert!(limit.try_acquire().is_none());
+
+        drop(first);
+        drop(second);
+        assert_eq!(1, limit.available());
+    }
+}
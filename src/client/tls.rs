@@ -0,0 +1,433 @@
+//! TLS settings for the connections to APNs
+
+use crate::client::ClientConfig;
+use crate::error::Error;
+use base64::prelude::*;
+use ring::digest;
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::{WantsClientCert, WebPkiServerVerifier};
+use rustls::crypto::{CryptoProvider, WebPkiSupportedAlgorithms};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::{CertificateError, ConfigBuilder, DigitallySignedStruct, RootCertStore, SignatureScheme};
+use std::sync::Arc;
+
+/// A certificate authority to trust when verifying the server, in addition to
+/// the bundled web PKI roots. Needed e.g. for connecting to a local APNs
+/// stand-in using a self-signed certificate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootCertificate {
+    der: Vec<u8>,
+}
+
+impl RootCertificate {
+    /// Reads a DER-encoded certificate.
+    pub fn from_der(der: impl Into<Vec<u8>>) -> Result<Self, Error> {
+        let der = der.into();
+        webpki::anchor_from_trusted_cert(&CertificateDer::from(der.as_slice()))
+            .map_err(|_| Error::InvalidCertificate)?;
+
+        Ok(Self { der })
+    }
+
+    /// Reads every certificate from a PEM file, which must hold at least one.
+    pub fn from_pem(mut pem: &[u8]) -> Result<Vec<Self>, Error> {
+        let certificates = rustls_pemfile::certs(&mut pem)
+            .map(|der| {
+                let der = der.map_err(|_| Error::InvalidCertificate)?;
+                Self::from_der(der.as_ref())
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        if certificates.is_empty() {
+            return Err(Error::InvalidCertificate);
+        }
+
+        Ok(certificates)
+    }
+
+    /// The DER-encoded certificate.
+    pub fn as_der(&self) -> &[u8] {
+        &self.der
+    }
+}
+
+/// A SHA-256 digest identifying a certificate the server must present or be
+/// issued by. When pins are configured, the server's certificate chain is
+/// still verified against the trusted roots, but is only accepted if it
+/// matches one of the pins.
+///
+/// A pin matches a certificate of the verified chain, from the server's
+/// certificate over the intermediates up to the trusted root. Certificates
+/// the server sends but the chain doesn't use never match.
+///
+/// The bundled web PKI roots are only known by their public key, so a
+/// [`Certificate`](CertificatePin::Certificate) pin never matches one of
+/// them. Pin a bundled root with [`CertificatePin::public_key`], or add its
+/// certificate to [`ClientConfig::root_certificates`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CertificatePin {
+    /// The digest of a whole DER-encoded certificate.
+    Certificate([u8; 32]),
+    /// The digest of a DER-encoded `SubjectPublicKeyInfo`. Keeps matching
+    /// when a certificate is renewed using the same key.
+    PublicKey([u8; 32]),
+}
+
+impl CertificatePin {
+    /// Pins the given DER-encoded certificate.
+    pub fn certificate(der: &[u8]) -> Self {
+        Self::Certificate(sha256(der))
+    }
+
+    /// Pins the public key of the given DER-encoded certificate.
+    pub fn public_key(der: &[u8]) -> Result<Self, Error> {
+        let der = CertificateDer::from(der);
+        let certificate = webpki::EndEntityCert::try_from(&der).map_err(|_| Error::InvalidCertificate)?;
+
+        Ok(Self::PublicKey(sha256(&certificate.subject_public_key_info())))
+    }
+
+    /// Parses a base64-encoded public key digest, the format used by
+    /// `openssl` and HTTP public key pinning (`pin-sha256`).
+    pub fn public_key_base64(pin: &str) -> Result<Self, Error> {
+        let digest = BASE64_STANDARD
+            .decode(pin)
+            .ok()
+            .and_then(|digest| <[u8; 32]>::try_from(digest).ok())
+            .ok_or(Error::InvalidCertificate)?;
+
+        Ok(Self::PublicKey(digest))
+    }
+}
+
+#[derive(Debug)]
+struct PinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    roots: Arc<RootCertStore>,
+    algorithms: WebPkiSupportedAlgorithms,
+    pins: Vec<CertificatePin>,
+    /// Public keys of the trusted roots whose certificate is pinned.
+    pinned_root_keys: Vec<Vec<u8>>,
+}
+
+impl PinningVerifier {
+    fn is_pinned(&self, der: &[u8], spki: &[u8]) -> bool {
+        self.pins.contains(&CertificatePin::Certificate(sha256(der)))
+            || self.pins.contains(&CertificatePin::PublicKey(sha256(spki)))
+    }
+
+    fn is_path_pinned(&self, path: &webpki::VerifiedPath<'_>) -> bool {
+        let end_entity = path.end_entity();
+        let anchor_spki = der_sequence(path.anchor().subject_public_key_info.as_ref());
+
+        self.is_pinned(&end_entity.der(), &end_entity.subject_public_key_info())
+            || path
+                .intermediate_certificates()
+                .any(|certificate| self.is_pinned(&certificate.der(), &certificate.subject_public_key_info()))
+            || self.pins.contains(&CertificatePin::PublicKey(sha256(&anchor_spki)))
+            || self.pinned_root_keys.contains(&anchor_spki)
+    }
+}
+
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self
+            .inner
+            .verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+
+        let pin_failure = || rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure);
+        let certificate = webpki::EndEntityCert::try_from(end_entity).map_err(|_| pin_failure())?;
+
+        // Builds the chains again, accepting only one with a pinned
+        // certificate. Path building tries the other chains when a pin
+        // doesn't match.
+        let require_pin = |path: &webpki::VerifiedPath<'_>| {
+            if self.is_path_pinned(path) {
+                Ok(())
+            } else {
+                Err(webpki::Error::UnknownIssuer)
+            }
+        };
+
+        certificate
+            .verify_for_usage(
+                self.algorithms.all,
+                &self.roots.roots,
+                intermediates,
+                now,
+                webpki::KeyUsage::server_auth(),
+                None,
+                Some(&require_pin),
+            )
+            .map_err(|_| pin_failure())?;
+
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
+/// A rustls configuration trusting the web PKI roots and the roots from the
+/// config, enforcing the pins if any.
+pub(crate) fn config_builder(
+    config: &ClientConfig,
+) -> Result<ConfigBuilder<rustls::ClientConfig, WantsClientCert>, Error> {
+    #[cfg(feature = "ring")]
+    {
+        let _ = ::rustls::crypto::ring::default_provider().install_default();
+    }
+
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    for root in config.root_certificates.iter() {
+        roots.add(CertificateDer::from(root.as_der()).into_owned())?;
+    }
+
+    let builder = rustls::ClientConfig::builder();
+
+    if config.pinned_certificates.is_empty() {
+        return Ok(builder.with_root_certificates(roots));
+    }
+
+    let verifier = pinning_verifier(roots, &config.root_certificates, config.pinned_certificates.clone())?;
+
+    Ok(builder.dangerous().with_custom_certificate_verifier(Arc::new(verifier)))
+}
+
+/// A verifier trusting `roots`, of which `root_certificates` are the ones
+/// known by their certificate, accepting only chains matching the pins.
+fn pinning_verifier(
+    roots: RootCertStore,
+    root_certificates: &[RootCertificate],
+    pins: Vec<CertificatePin>,
+) -> Result<PinningVerifier, Error> {
+    let mut pinned_root_keys = Vec::new();
+
+    for root in root_certificates {
+        if pins.contains(&CertificatePin::certificate(root.as_der())) {
+            let der = CertificateDer::from(root.as_der());
+            let anchor = webpki::anchor_from_trusted_cert(&der).map_err(|_| Error::InvalidCertificate)?;
+            pinned_root_keys.push(der_sequence(anchor.subject_public_key_info.as_ref()));
+        }
+    }
+
+    let algorithms = CryptoProvider::get_default()
+        .map(|provider| provider.signature_verification_algorithms)
+        .ok_or_else(|| rustls::Error::General("no default crypto provider".to_string()))?;
+
+    let roots = Arc::new(roots);
+    let inner = WebPkiServerVerifier::builder(roots.clone())
+        .build()
+        .map_err(|e| rustls::Error::General(e.to_string()))?;
+
+    Ok(PinningVerifier {
+        inner,
+        roots,
+        algorithms,
+        pins,
+        pinned_root_keys,
+    })
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut output = [0; 32];
+    output.copy_from_slice(digest::digest(&digest::SHA256, data).as_ref());
+    output
+}
+
+/// Wraps the contents of a DER `SEQUENCE` with its tag and length.
+fn der_sequence(contents: &[u8]) -> Vec<u8> {
+    let mut der = vec![0x30];
+    let len = contents.len();
+
+    if len < 0x80 {
+        der.push(len as u8);
+    } else {
+        let len_bytes: Vec<u8> = len.to_be_bytes().into_iter().skip_while(|b| *b == 0).collect();
+        der.push(0x80 | len_bytes.len() as u8);
+        der.extend(len_bytes);
+    }
+
+    der.extend_from_slice(contents);
+    der
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CA_CERTIFICATE: &str = include_str!("../../test_cert/ca.crt");
+    const SERVER_CERTIFICATE: &str = include_str!("../../test_cert/localhost.crt");
+
+    fn der(pem: &str) -> Vec<u8> {
+        RootCertificate::from_pem(pem.as_bytes()).unwrap()[0].as_der().to_vec()
+    }
+
+    /// Runs a handshake with a server sending the given certificate chain,
+    /// trusting `ca.crt` and enforcing the pins.
+    async fn handshake(chain: Vec<Vec<u8>>, pins: Vec<CertificatePin>) -> Result<(), std::io::Error> {
+        let config = ClientConfig {
+            root_certificates: RootCertificate::from_pem(CA_CERTIFICATE.as_bytes()).unwrap(),
+            pinned_certificates: pins,
+            ..Default::default()
+        };
+
+        handshake_with(config_builder(&config).unwrap().with_no_client_auth(), chain).await
+    }
+
+    async fn handshake_with(client_config: rustls::ClientConfig, chain: Vec<Vec<u8>>) -> Result<(), std::io::Error> {
+        let mut key: &[u8] = include_bytes!("../../test_cert/localhost.key");
+        let key = rustls_pemfile::private_key(&mut key).unwrap().unwrap();
+        let chain = chain.into_iter().map(CertificateDer::from).collect();
+
+        let server_config =
+            rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(chain, key)
+                .unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(Arc::new(server_config));
+        let connector = tokio_rustls::TlsConnector::from(Arc::new(client_config));
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(async move { acceptor.accept(server).await });
+
+        let server_name = ServerName::try_from("localhost").unwrap();
+        connector.connect(server_name, client).await.map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn test_pins_match_the_verified_chain() {
+        let leaf = der(SERVER_CERTIFICATE);
+        let ca = der(CA_CERTIFICATE);
+
+        assert!(handshake(vec![leaf.clone()], vec![CertificatePin::certificate(&ca)])
+            .await
+            .is_ok());
+        assert!(
+            handshake(vec![leaf.clone()], vec![CertificatePin::public_key(&ca).unwrap()])
+                .await
+                .is_ok()
+        );
+        assert!(
+            handshake(vec![leaf.clone()], vec![CertificatePin::public_key(&leaf).unwrap()])
+                .await
+                .is_ok()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_pinned_certificate_outside_the_chain_is_rejected() {
+        let leaf = der(SERVER_CERTIFICATE);
+        let unrelated = der(include_str!("../../test_cert/test.crt"));
+        let pins = vec![CertificatePin::certificate(&unrelated)];
+
+        let result = handshake(vec![leaf, unrelated.clone()], pins).await;
+
+        let error = result.unwrap_err();
+        let tls_error = error.get_ref().and_then(|e| e.downcast_ref::<rustls::Error>()).unwrap();
+        assert_eq!(
+            &rustls::Error::InvalidCertificate(CertificateError::ApplicationVerificationFailure),
+            tls_error
+        );
+    }
+
+    #[tokio::test]
+    async fn test_certificate_pin_of_a_bundled_root_never_matches() {
+        let leaf = der(SERVER_CERTIFICATE);
+        let ca = der(CA_CERTIFICATE);
+        let _ = rustls::crypto::ring::default_provider().install_default();
+
+        // Trusts `ca.crt` like a bundled root, known only by its public key.
+        let bundled = |pin: CertificatePin| {
+            let mut roots = RootCertStore::empty();
+            roots.add(CertificateDer::from(ca.as_slice())).unwrap();
+            let verifier = pinning_verifier(roots, &[], vec![pin]).unwrap();
+
+            rustls::ClientConfig::builder()
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+                .with_no_client_auth()
+        };
+
+        assert!(
+            handshake_with(bundled(CertificatePin::certificate(&ca)), vec![leaf.clone()])
+                .await
+                .is_err()
+        );
+        assert!(
+            handshake_with(bundled(CertificatePin::public_key(&ca).unwrap()), vec![leaf])
+                .await
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn test_root_certificate_from_pem() {
+        let roots = RootCertificate::from_pem(CA_CERTIFICATE.as_bytes()).unwrap();
+        assert_eq!(1, roots.len());
+
+        assert!(matches!(
+            RootCertificate::from_pem(b"not a certificate"),
+            Err(Error::InvalidCertificate)
+        ));
+        assert!(matches!(
+            RootCertificate::from_der(b"not a certificate".to_vec()),
+            Err(Error::InvalidCertificate)
+        ));
+    }
+
+    #[test]
+    fn test_public_key_pin_matches_base64() {
+        let pin = CertificatePin::public_key(&der(SERVER_CERTIFICATE)).unwrap();
+
+        let CertificatePin::PublicKey(digest) = pin else {
+            panic!("expected a public key pin");
+        };
+
+        assert_eq!(
+            pin,
+            CertificatePin::public_key_base64(&BASE64_STANDARD.encode(digest)).unwrap()
+        );
+        assert!(CertificatePin::public_key_base64("dG9vIHNob3J0").is_err());
+    }
+
+    #[test]
+    fn test_der_sequence_wraps_long_contents() {
+        assert_eq!(vec![0x30, 0x01, 0xff], der_sequence(&[0xff]));
+
+        let wrapped = der_sequence(&[0; 300]);
+        assert_eq!(&[0x30, 0x82, 0x01, 0x2c], &wrapped[..4]);
+        assert_eq!(304, wrapped.len());
+    }
+}
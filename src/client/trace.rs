@@ -0,0 +1,53 @@
+//! Tracing spans for the notifications sent to APNs
+
+use super::TokenRedaction;
+use crate::error::Error;
+use crate::response::Response;
+use hyper::body::Bytes;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// A span covering all attempts of sending a notification, with the options
+/// of the notification taken from the request headers. The payload is never
+/// recorded.
+pub(crate) fn send_span(redaction: TokenRedaction, device_token: &str, request: &http::Request<Bytes>) -> Span {
+    let header = |name: &str| request.headers().get(name).and_then(|value| value.to_str().ok());
+
+    tracing::info_span!(
+        "apns_send",
+        device_token = redaction.redact(device_token),
+        topic = header("apns-topic"),
+        push_type = header("apns-push-type"),
+        priority = header("apns-priority"),
+        apns_id = header("apns-id"),
+        attempts = Empty,
+        status = Empty,
+        reason = Empty,
+    )
+}
+
+/// A span covering one attempt of sending a notification.
+pub(crate) fn attempt_span(attempt: u32) -> Span {
+    tracing::debug_span!("apns_attempt", attempt, status = Empty, reason = Empty)
+}
+
+/// Records the status and the reason of a failure in the span.
+pub(crate) fn record_result(span: &Span, result: &Result<Response, Error>) {
+    let response = match result {
+        Ok(response) | Err(Error::ResponseError(response)) => response,
+        Err(error) => {
+            span.record("reason", tracing::field::display(error));
+            return;
+        }
+    };
+
+    span.record("status", response.code);
+
+    if let Some(ref body) = response.error {
+        span.record("reason", tracing::field::debug(&body.reason));
+    }
+
+    if let Some(ref apns_id) = response.apns_id {
+        span.record("apns_id", apns_id.as_str());
+    }
+}
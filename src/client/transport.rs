@@ -0,0 +1,77 @@
+//! Sending the HTTP/2 requests to APNs
+
+use super::pool::Pool;
+use crate::error::Error;
+use http_body_util::{BodyExt, Full};
+use hyper::body::Bytes;
+use hyper_util::client::legacy::connect::Connect;
+use hyper_util::client::legacy::Client as HttpClient;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+
+/// The future returned by [`Transport::send`].
+pub type TransportFuture<'a> = Pin<Box<dyn Future<Output = Result<http::Response<Bytes>, Error>> + Send + 'a>>;
+
+/// Sends the requests of a [`Client`](super::Client) over HTTP/2, replacing
+/// the built-in connection pool, e.g. for recording requests in tests,
+/// routing through a sidecar or reusing a hyper client of the application.
+/// Set with [`ClientBuilder::transport`](super::ClientBuilder::transport).
+///
+/// The client still signs the requests, builds the headers, applies the
+/// timeouts, retries and limits, and maps the responses. The transport gets
+/// complete requests, with an absolute URI, and returns the status, headers
+/// and body of the response.
+///
+/// A hyper client sending `Full<Bytes>` bodies is a transport:
+///
+/// ```no_run
+/// # use a2::Client;
+/// # use http_body_util::Full;
+/// # use hyper::body::Bytes;
+/// # use hyper_util::client::legacy::Client as HttpClient;
+/// # use hyper_util::rt::TokioExecutor;
+/// # fn build(connector: hyper_util::client::legacy::connect::HttpConnector) -> Result<Client, a2::Error> {
+/// let http_client: HttpClient<_, Full<Bytes>> = HttpClient::builder(TokioExecutor::new())
+///     .http2_only(true)
+///     .build(connector);
+///
+/// Client::builder().transport(http_client).build()
+/// # }
+/// ```
+pub trait Transport: fmt::Debug + Send + Sync + 'static {
+    /// Sends the request, resolving to the response with its whole body.
+    fn send(&self, request: http::Request<Bytes>) -> TransportFuture<'_>;
+}
+
+impl<C> Transport for HttpClient<C, Full<Bytes>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+{
+    fn send(&self, request: http::Request<Bytes>) -> TransportFuture<'_> {
+        let requesting = self.request(request.map(Full::new));
+
+        Box::pin(async move {
+            let (parts, body) = requesting.await?.into_parts();
+            let body = body.collect().await?.to_bytes();
+
+            Ok(http::Response::from_parts(parts, body))
+        })
+    }
+}
+
+/// The built-in transport, spreading the requests over the connections of
+/// the pool.
+impl Transport for Pool {
+    fn send(&self, request: http::Request<Bytes>) -> TransportFuture<'_> {
+        Box::pin(async move {
+            let (parts, body) = self
+                .request(request.map(|body| Full::new(body).boxed()))
+                .await?
+                .into_parts();
+            let body = body.collect().await?.to_bytes();
+
+            Ok(http::Response::from_parts(parts, body))
+        })
+    }
+}
@@ -1,6 +1,7 @@
 /// Error and result module
 use crate::{response::Response, signer::SignerError};
 use std::io;
+use std::time::Duration;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -16,6 +17,16 @@ pub enum Error {
     #[error("Http client error: {0}")]
     ClientError(#[from] hyper_util::client::legacy::Error),
 
+    /// Opening a connection to APNs failed, in the TCP or TLS handshake or
+    /// the proxy tunnel.
+    #[error("Error opening a connection to APNs: {0}")]
+    ConnectError(Box<dyn std::error::Error + Send + Sync>),
+
+    /// A custom [Transport](client/trait.Transport.html) failed to send the
+    /// request or to receive the response.
+    #[error("Transport error: {0}")]
+    Transport(Box<dyn std::error::Error + Send + Sync>),
+
     /// Couldn't generate an APNs token with the given key.
     #[error("Error creating a signature: {0}")]
     SignerError(#[from] SignerError),
@@ -37,6 +48,24 @@ pub enum Error {
     #[error("Invalid options for APNs payload: {0}")]
     InvalidOptions(String),
 
+    /// The given [Endpoint](client/enum.Endpoint.html) could not be parsed.
+    #[error("Invalid APNs endpoint: {0}")]
+    InvalidEndpoint(String),
+
+    /// The given [Proxy](client/struct.Proxy.html) could not be parsed.
+    #[error("Invalid proxy: {0}")]
+    InvalidProxy(String),
+
+    /// The [ClientSettings](client/struct.ClientSettings.html) could not be
+    /// read.
+    #[error("Invalid client configuration: {0}")]
+    InvalidConfig(String),
+
+    /// The options of the [ClientBuilder](client/struct.ClientBuilder.html)
+    /// can't be combined.
+    #[error("Invalid client options: {0}")]
+    InvalidBuilder(#[from] BuilderError),
+
     /// Error reading the certificate or private key.
     #[error("Error in reading a certificate file: {0}")]
     ReadError(#[from] io::Error),
@@ -49,14 +78,93 @@ pub enum Error {
     BuildRequestError(#[source] http::Error),
 
     /// No repsonse from APNs after the given amount of time
-    #[error("The request timed out after {0} s")]
-    RequestTimeout(u64),
+    #[error("The request timed out after {0:?}")]
+    RequestTimeout(Duration),
+
+    /// Too many requests are waiting for a free stream to APNs. Slow down
+    /// and try again later.
+    #[error("The send queue is full")]
+    QueueFull,
+
+    /// The device token is over the
+    /// [DeviceRateLimit](client/struct.DeviceRateLimit.html). The
+    /// notification was not sent.
+    #[error("Too many notifications for the device token")]
+    DeviceRateLimited,
+
+    /// Too many requests to APNs failed recently, so the
+    /// [CircuitBreaker](client/struct.CircuitBreaker.html) stopped sending
+    /// for a while.
+    #[error("The circuit breaker is open")]
+    CircuitOpen,
+
+    /// The [Client](client/struct.Client.html) is shutting down, either
+    /// refusing a new notification or abandoning one still in flight at the
+    /// deadline.
+    #[error("The client is shutting down")]
+    Shutdown,
+
+    /// The [ClientRegistry](client/struct.ClientRegistry.html) has no client
+    /// for the tenant or the topic, which is empty for a notification without
+    /// a topic.
+    #[error("No client for the tenant or topic {0:?}")]
+    UnknownTenant(String),
 
     /// Unexpected private key (only EC keys are supported).
-    #[cfg(all(not(feature = "openssl"), feature = "ring"))]
+    #[cfg(feature = "ring")]
     #[error("Unexpected private key: {0}")]
     UnexpectedKey(#[from] ring::error::KeyRejected),
 
     #[error("Invalid certificate")]
     InvalidCertificate,
 }
+
+/// Why the options given to a [ClientBuilder](../client/struct.ClientBuilder.html)
+/// were rejected.
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum BuilderError {
+    /// An HTTP/2 flow control window larger than 2^31-1 bytes.
+    #[error("the HTTP/2 window size {0} is larger than 2^31-1")]
+    InvalidWindowSize(u32),
+
+    /// An HTTP/2 max frame size outside of 16 KiB to 16 MiB.
+    #[error("the HTTP/2 max frame size {0} is not between 16384 and 16777215")]
+    InvalidMaxFrameSize(u32),
+
+    /// The adaptive window replaces the initial window sizes.
+    #[error("the HTTP/2 adaptive window can't be combined with initial window sizes")]
+    AdaptiveWindowWithFixedSize,
+
+    /// A connect timeout of zero fails every connection.
+    #[error("the connect timeout must not be zero")]
+    ZeroConnectTimeout,
+
+    /// A request timeout of zero fails every request.
+    #[error("the request timeout must not be zero")]
+    ZeroRequestTimeout,
+
+    /// TLS options were given for a plaintext endpoint.
+    #[error("TLS options were given for an http endpoint")]
+    TlsWithoutHttps,
+
+    /// A custom TLS config replaces the root certificates and pins.
+    #[error("a custom TLS config can't be combined with root certificates or pins")]
+    TlsConfigWithCertificates,
+
+    /// A custom TLS config replaces the client certificate.
+    #[error("a custom TLS config can't be combined with certificate credentials")]
+    TlsConfigWithClientCertificate,
+
+    /// A custom transport opens the connections itself.
+    #[error("a custom transport can't be combined with TLS, proxy, HTTP/2 or TCP options")]
+    TransportWithConnectionOptions,
+
+    /// A custom transport presents its own client certificate, if any.
+    #[error("a custom transport can't be combined with certificate credentials")]
+    TransportWithClientCertificate,
+
+    /// A dry run records the requests instead of sending them with the
+    /// transport.
+    #[error("a dry run can't be combined with a custom transport")]
+    DryRunWithTransport,
+}
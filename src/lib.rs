@@ -108,8 +108,8 @@
 //! ```
 #![warn(clippy::unwrap_used)]
 
-#[cfg(not(any(feature = "openssl", feature = "ring")))]
-compile_error!("either feature \"openssl\" or feature \"ring\" has to be enabled");
+#[cfg(not(feature = "ring"))]
+compile_error!("feature \"ring\" has to be enabled");
 
 #[macro_use]
 extern crate serde;
@@ -134,6 +134,6 @@ pub use crate::request::payload::InterruptionLevel;
 
 pub use crate::response::{ErrorBody, ErrorReason, Response};
 
-pub use crate::client::{Client, ClientConfig, Endpoint};
+pub use crate::client::{Client, ClientConfig, Endpoint, Proxy, Scheme, SendOptions};
 
 pub use crate::error::Error;
@@ -1,13 +1,18 @@
 /// Payload with `aps` and custom data
+use crate::client::TokenRedaction;
 use crate::error::Error;
 use crate::request::notification::{DefaultAlert, DefaultSound, NotificationOptions, WebPushAlert};
 use erased_serde::Serialize;
 use serde_json::{self, Value};
 use std::collections::BTreeMap;
-use std::fmt::Debug;
+use std::fmt::{self, Debug};
 
 /// The data and options for a push notification.
-#[derive(Debug, Clone, Serialize)]
+///
+/// The `Debug` output shows the device token hashed like
+/// [`TokenRedaction::Hashed`], the client's default for tracing, and none of
+/// the notification content.
+#[derive(Clone, Serialize)]
 pub struct Payload<'a> {
     /// Send options
     #[serde(skip)]
@@ -22,6 +27,19 @@ pub struct Payload<'a> {
     pub data: BTreeMap<&'a str, Value>,
 }
 
+impl Debug for Payload<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let device_token = TokenRedaction::Hashed.redact(self.device_token).unwrap_or_default();
+
+        f.debug_struct("Payload")
+            .field("options", &self.options)
+            .field("device_token", &device_token)
+            .field("aps", &"<redacted>")
+            .field("data", &"<redacted>")
+            .finish()
+    }
+}
+
 /// Object that can be serialized to create an APNS request.
 /// You probably just want to use [`Payload`], which implements [`PayloadLike`].
 ///
@@ -270,6 +288,19 @@ mod tests {
     use super::*;
     use crate::request::notification::{DefaultNotificationBuilder, NotificationBuilder};
 
+    #[test]
+    fn test_debug_hides_token_and_content() {
+        let payload = DefaultNotificationBuilder::new()
+            .set_body("A secret message")
+            .build("a0b1c2d3e4f5a6b7c8d9", Default::default());
+
+        let debug = format!("{:?}", payload);
+
+        assert!(debug.contains(&TokenRedaction::Hashed.redact("a0b1c2d3e4f5a6b7c8d9").unwrap()));
+        assert!(!debug.contains("a0b1c2d3"));
+        assert!(!debug.contains("A secret message"));
+    }
+
     #[test]
     fn test_interruption_level_serialization() {
         let builder = DefaultNotificationBuilder::new()
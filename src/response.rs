@@ -46,7 +46,7 @@ pub struct ErrorBody {
 }
 
 /// A description what went wrong with the push notification.
-#[derive(Deserialize, Debug, PartialEq, Eq)]
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum ErrorReason {
     /// The collapse identifier exceeds the maximum allowed size.
     BadCollapseId,
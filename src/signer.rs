@@ -1,5 +1,6 @@
 use crate::error::Error;
 use parking_lot::RwLock;
+use std::fmt;
 use std::io::Read;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
@@ -8,7 +9,7 @@ use base64::prelude::*;
 use ring::{rand, signature};
 use thiserror::Error;
 
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 struct Signature {
     key: String,
     issued_at: i64,
@@ -16,7 +17,7 @@ struct Signature {
 
 /// For signing requests when using token-based authentication. Re-uses the same
 /// signature for a certain amount of time.
-#[derive(Debug, Clone)]
+#[derive(Clone)]
 pub struct Signer {
     signature: Arc<RwLock<Signature>>,
     key_id: String,
@@ -25,6 +26,17 @@ pub struct Signer {
     expire_after_s: Duration,
 }
 
+/// Shows the key and team ids, never the private key or the signature.
+impl fmt::Debug for Signer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Signer")
+            .field("key_id", &self.key_id)
+            .field("team_id", &self.team_id)
+            .field("expire_after_s", &self.expire_after_s)
+            .finish_non_exhaustive()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 enum JwtAlg {
     ES256,
@@ -163,6 +175,11 @@ impl Signer {
             );
         }
 
+        #[cfg(feature = "metrics")]
+        {
+            metrics::counter!("a2_token_renewals_total").increment(1);
+        }
+
         let mut signature = self.signature.write();
 
         *signature = Signature {
@@ -182,25 +199,19 @@ impl Signer {
 
 impl Secret {
     fn sign(&self, signing_input: &String) -> Result<Vec<u8>, SignerError> {
-        if let Secret::Ring { signing_key, rng } = self {
-            let sig = signing_key.sign(rng, signing_input.as_bytes())?;
-            Ok(sig.as_ref().to_vec())
-        } else {
-            unreachable!()
-        }
+        let Secret::Ring { signing_key, rng } = self;
+        let sig = signing_key.sign(rng, signing_input.as_bytes())?;
+        Ok(sig.as_ref().to_vec())
     }
 }
 
 /// Failed to sign payload
 #[derive(Debug, Error)]
 pub enum SignerError {
-    #[cfg(feature = "openssl")]
-    #[error(transparent)]
-    OpenSSL(#[from] openssl::error::ErrorStack),
-    #[cfg(all(not(feature = "openssl"), feature = "ring"))]
+    #[cfg(feature = "ring")]
     #[error(transparent)]
     Pem(#[from] pem::PemError),
-    #[cfg(all(not(feature = "openssl"), feature = "ring"))]
+    #[cfg(feature = "ring")]
     #[error(transparent)]
     Ring(#[from] ring::error::Unspecified),
 }
@@ -222,6 +233,24 @@ lCEIvbDqlUhA5FOzcakkG90E8L+hRANCAATKS2ZExEybUvchRDuKBftotMwVEus3
 jDwmlD1Gg0yJt1e38djFwsxsfr5q2hv0Rj9fTEqAPr8H7mGm0wKxZ7iQ
 -----END PRIVATE KEY-----";
 
+    #[test]
+    fn test_debug_hides_the_signature() {
+        let signer = Signer::new(
+            PRIVATE_KEY.as_bytes(),
+            "89AFRD1X22",
+            "ASDFQWERTY",
+            Duration::from_secs(100),
+        )
+        .unwrap();
+
+        let debug = format!("{:?}", signer);
+        assert!(debug.contains("89AFRD1X22"));
+
+        signer
+            .with_signature(|signature| assert!(!debug.contains(signature)))
+            .unwrap();
+    }
+
     #[test]
     fn test_signature_caching() {
         let signer = Signer::new(